@@ -0,0 +1,63 @@
+// Host program (std feature): writes a MIFARE Classic 1K dump in every dump_formats format,
+// reads each file back and checks that blocks, UID and unreadable blocks survive.
+//     cargo run --features std --example dump_roundtrip --target x86_64-unknown-linux-gnu -Z build-std=std,panic_abort
+
+use rfid_rc522::card_types::{CardType, Uid};
+use rfid_rc522::dump::ClassicDump;
+use rfid_rc522::dump_formats::{
+    from_flipper_nfc, from_hex_text, from_mfd, from_proxmark_json, to_flipper_nfc, to_hex_text, to_mfd,
+    to_proxmark_json, DumpFormatError,
+};
+
+const BLOCKS: u8 = 64;
+const UNREADABLE_BLOCK: u8 = 5;
+
+fn sample_dump() -> ClassicDump {
+    let mut uid = Uid::new();
+    uid.bytes[..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+    uid.size = 4;
+    uid.sak = 0x08;
+
+    let mut dump = ClassicDump::new(uid, CardType::Mifare1K);
+    // Manufacturer block: UID, BCC, SAK, ATQA and manufacturer data
+    dump.blocks[0] = [0xDE, 0xAD, 0xBE, 0xEF, 0x22, 0x08, 0x04, 0x00, 1, 2, 3, 4, 5, 6, 7, 8];
+    for block in 1..BLOCKS {
+        dump.blocks[block as usize] = [block; 16];
+    }
+    dump.set_readable(UNREADABLE_BLOCK, false);
+    dump.blocks[UNREADABLE_BLOCK as usize] = [0u8; 16];
+    dump
+}
+
+// `keeps_unreadable` is false for the formats without a marker for unknown bytes
+fn check(name: &str, dump: &ClassicDump, parsed: Result<ClassicDump, DumpFormatError>, keeps_unreadable: bool) -> bool {
+    let parsed = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("{name}: import failed: {err:?}");
+            return false;
+        }
+    };
+    let mut ok = parsed.uid.as_bytes() == dump.uid.as_bytes();
+    for block in 0..BLOCKS {
+        ok &= parsed.blocks[block as usize] == dump.blocks[block as usize];
+        if keeps_unreadable {
+            ok &= parsed.is_readable(block) == dump.is_readable(block);
+        }
+    }
+    println!("{name}: {}", if ok { "ok" } else { "MISMATCH" });
+    ok
+}
+
+fn main() {
+    let dump = sample_dump();
+    let results = [
+        check("mfd", &dump, from_mfd(&to_mfd(&dump)), false),
+        check("proxmark json", &dump, from_proxmark_json(&to_proxmark_json(&dump)), false),
+        check("flipper nfc", &dump, from_flipper_nfc(&to_flipper_nfc(&dump)), true),
+        check("hex text", &dump, from_hex_text(&to_hex_text(&dump)), true),
+    ];
+    if results.contains(&false) {
+        std::process::exit(1);
+    }
+}
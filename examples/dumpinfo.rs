@@ -4,6 +4,8 @@
 use arduino_hal::spi;
 use arduino_hal::default_serial;
 use rfid_rc522::RfidRc522;
+use rfid_rc522::card_types::Uid;
+use rfid_rc522::mifare::Key;
 use embedded_hal::spi::{Mode, Phase, Polarity};
 use panic_halt as _;
 use ufmt::uwriteln;
@@ -62,21 +64,38 @@ fn main() -> ! {
             
         }
 
-        // Step 2: Read the UID of the detected card
-        match rfid.read_card_serial(&mut serial) {
-            Ok(Some(uid)) => {
-                uwriteln!(&mut serial, "Card UID:").ok();
-                for byte in &uid {
-                    uwriteln!(&mut serial, "{:02X}", *byte).ok();
+        // Step 2: Select the card and read its UID
+        let mut uid = Uid::new();
+        if rfid.picc_select(&mut serial, &mut uid, 0).is_err() {
+            uwriteln!(&mut serial, "Failed to read UID.").ok();
+            arduino_hal::delay_ms(1500);
+            continue;
+        }
+        uwriteln!(&mut serial, "Card UID:").ok();
+        for byte in uid.as_bytes() {
+            uwriteln!(&mut serial, "{:02X}", *byte).ok();
+        }
+
+        // Step 3: Dump every sector readable with the factory default key
+        let result = rfid.dump_classic_with(&mut serial, &uid, &[Key::DEFAULT], |serial, block, data, _| {
+            match data {
+                Some(data) => {
+                    uwriteln!(
+                        serial,
+                        "Block {}: {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X}",
+                        block, data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7],
+                        data[8], data[9], data[10], data[11], data[12], data[13], data[14], data[15]
+                    ).ok();
+                }
+                None => {
+                    uwriteln!(serial, "Block {}: unreadable", block).ok();
                 }
             }
-            Ok(None) => {
-                uwriteln!(&mut serial, "Failed to read UID.").ok();
-            }
-            Err(_) => {
-                uwriteln!(&mut serial, "Error reading card UID.").ok();
-            }
+        });
+        if result.is_err() {
+            uwriteln!(&mut serial, "Error dumping card.").ok();
         }
+
         // Delay before the next detection attempt
         arduino_hal::delay_ms(1500);
     }
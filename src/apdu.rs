@@ -0,0 +1,325 @@
+// src/apdu.rs
+// ISO/IEC 7816-4 APDUs over ISO-DEP: command and response APDUs with short and extended length
+// fields, status word decoding, and the SELECT / READ BINARY / UPDATE BINARY commands used by
+// Java Card applets and Type 4 Tags. 61xx (more data) and 6Cxx (wrong Le) are handled here so
+// callers only ever see the final answer.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::errors::RFIDError;
+use crate::rfid_rc522::RfidRc522;
+
+pub const CLA_ISO: u8 = 0x00;
+
+pub const INS_SELECT: u8 = 0xA4;
+pub const INS_READ_BINARY: u8 = 0xB0;
+pub const INS_UPDATE_BINARY: u8 = 0xD6;
+pub const INS_GET_RESPONSE: u8 = 0xC0;
+
+// SELECT P1: by file identifier or by DF name (AID)
+pub const SELECT_BY_FILE_ID: u8 = 0x00;
+pub const SELECT_BY_NAME: u8 = 0x04;
+// SELECT P2: first or only occurrence, return the FCI or nothing
+pub const SELECT_RETURN_FCI: u8 = 0x00;
+pub const SELECT_NO_RESPONSE: u8 = 0x0C;
+
+// Largest short Le and extended Le
+pub const SHORT_LE_MAX: usize = 256;
+pub const EXTENDED_LE_MAX: usize = 65536;
+
+// Room for a command with 255 data bytes and an extended Le
+pub const APDU_BUFFER_SIZE: usize = 264;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct CommandApdu<'a> {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: &'a [u8],
+    pub le: Option<usize>, // Expected answer length, 256 (65536 extended) for "all available"
+}
+
+impl<'a> CommandApdu<'a> {
+    pub const fn new(cla: u8, ins: u8, p1: u8, p2: u8) -> Self {
+        CommandApdu { cla, ins, p1, p2, data: &[], le: None }
+    }
+
+    pub const fn with_data(mut self, data: &'a [u8]) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub const fn with_le(mut self, le: usize) -> Self {
+        self.le = Some(le);
+        self
+    }
+
+    // Extended length fields are needed for more than 255 data bytes or more than 256 expected
+    pub fn is_extended(&self) -> bool {
+        self.data.len() > 255 || self.le.is_some_and(|le| le > SHORT_LE_MAX)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        let lc = match (self.data.is_empty(), self.is_extended()) {
+            (true, _) => 0,
+            (false, false) => 1,
+            (false, true) => 3,
+        };
+        let le = match (self.le, self.is_extended()) {
+            (None, _) => 0,
+            (Some(_), false) => 1,
+            (Some(_), true) if self.data.is_empty() => 3,
+            (Some(_), true) => 2,
+        };
+        4 + lc + self.data.len() + le
+    }
+
+    // Serializes the APDU into `buffer`, returns its length
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+        if self.data.len() > u16::MAX as usize || self.le.is_some_and(|le| le == 0 || le > EXTENDED_LE_MAX) {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let length = self.encoded_len();
+        let buffer = buffer.get_mut(..length).ok_or(RFIDError::NoRoom)?;
+        buffer[..4].copy_from_slice(&[self.cla, self.ins, self.p1, self.p2]);
+
+        let extended = self.is_extended();
+        let mut pos = 4;
+        if !self.data.is_empty() {
+            if extended {
+                buffer[pos] = 0x00;
+                buffer[pos + 1..pos + 3].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+                pos += 3;
+            } else {
+                buffer[pos] = self.data.len() as u8;
+                pos += 1;
+            }
+            buffer[pos..pos + self.data.len()].copy_from_slice(self.data);
+            pos += self.data.len();
+        }
+        // The maximum Le is encoded as 0
+        if let Some(le) = self.le {
+            if !extended {
+                buffer[pos] = le as u8;
+            } else {
+                if self.data.is_empty() {
+                    buffer[pos] = 0x00;
+                    pos += 1;
+                }
+                buffer[pos..pos + 2].copy_from_slice(&(le as u16).to_be_bytes());
+            }
+        }
+        Ok(length)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct ResponseApdu<'a> {
+    pub data: &'a [u8],
+    pub sw1: u8,
+    pub sw2: u8,
+}
+
+impl<'a> ResponseApdu<'a> {
+    // Splits a raw answer into data and status word
+    pub fn parse(response: &'a [u8]) -> Result<Self, RFIDError> {
+        if response.len() < 2 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let (data, sw) = response.split_at(response.len() - 2);
+        Ok(ResponseApdu { data, sw1: sw[0], sw2: sw[1] })
+    }
+
+    pub fn sw(&self) -> u16 {
+        u16::from_be_bytes([self.sw1, self.sw2])
+    }
+
+    pub fn status(&self) -> StatusWord {
+        StatusWord::from_sw(self.sw1, self.sw2)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.sw() == 0x9000
+    }
+
+    // The data if the command succeeded, RFIDError::Status otherwise
+    pub fn check(&self) -> Result<&'a [u8], RFIDError> {
+        if !self.is_success() {
+            return Err(RFIDError::Status(self.sw()));
+        }
+        Ok(self.data)
+    }
+}
+
+// Meaning of a status word as defined by ISO/IEC 7816-4
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StatusWord {
+    Success,                       // 9000
+    MoreData(u8),                  // 61xx - xx bytes left, fetch with GET RESPONSE
+    WarningUnchanged(u8),          // 62xx - state of non-volatile memory unchanged
+    WarningChanged(u8),            // 63xx - state of non-volatile memory changed, 63Cx: x retries left
+    ExecutionErrorUnchanged(u8),   // 64xx
+    ExecutionErrorChanged(u8),     // 65xx
+    SecurityError(u8),             // 66xx
+    WrongLength,                   // 6700
+    FunctionInClaNotSupported(u8), // 68xx - e.g. logical channel or secure messaging
+    SecurityStatusNotSatisfied,    // 6982
+    AuthenticationBlocked,         // 6983
+    ReferenceDataNotUsable,        // 6984
+    ConditionsNotSatisfied,        // 6985
+    CommandNotAllowed,             // 6986 - no current EF
+    CommandNotAllowedOther(u8),    // 69xx
+    WrongData,                     // 6A80
+    FunctionNotSupported,          // 6A81
+    FileNotFound,                  // 6A82 - also "application not found"
+    RecordNotFound,                // 6A83
+    NotEnoughMemory,               // 6A84
+    IncorrectP1P2,                 // 6A86
+    ReferencedDataNotFound,        // 6A88
+    WrongParametersOther(u8),      // 6Axx
+    WrongP1P2,                     // 6B00 - e.g. offset outside the EF
+    WrongLe(u8),                   // 6Cxx - xx is the exact length available
+    InsNotSupported,               // 6D00
+    ClaNotSupported,               // 6E00
+    NoPreciseDiagnosis,            // 6F00
+    Unknown(u16),
+}
+
+impl StatusWord {
+    pub fn from_sw(sw1: u8, sw2: u8) -> StatusWord {
+        match (sw1, sw2) {
+            (0x90, 0x00) => StatusWord::Success,
+            (0x61, n) => StatusWord::MoreData(n),
+            (0x62, n) => StatusWord::WarningUnchanged(n),
+            (0x63, n) => StatusWord::WarningChanged(n),
+            (0x64, n) => StatusWord::ExecutionErrorUnchanged(n),
+            (0x65, n) => StatusWord::ExecutionErrorChanged(n),
+            (0x66, n) => StatusWord::SecurityError(n),
+            (0x67, 0x00) => StatusWord::WrongLength,
+            (0x68, n) => StatusWord::FunctionInClaNotSupported(n),
+            (0x69, 0x82) => StatusWord::SecurityStatusNotSatisfied,
+            (0x69, 0x83) => StatusWord::AuthenticationBlocked,
+            (0x69, 0x84) => StatusWord::ReferenceDataNotUsable,
+            (0x69, 0x85) => StatusWord::ConditionsNotSatisfied,
+            (0x69, 0x86) => StatusWord::CommandNotAllowed,
+            (0x69, n) => StatusWord::CommandNotAllowedOther(n),
+            (0x6A, 0x80) => StatusWord::WrongData,
+            (0x6A, 0x81) => StatusWord::FunctionNotSupported,
+            (0x6A, 0x82) => StatusWord::FileNotFound,
+            (0x6A, 0x83) => StatusWord::RecordNotFound,
+            (0x6A, 0x84) => StatusWord::NotEnoughMemory,
+            (0x6A, 0x86) => StatusWord::IncorrectP1P2,
+            (0x6A, 0x88) => StatusWord::ReferencedDataNotFound,
+            (0x6A, n) => StatusWord::WrongParametersOther(n),
+            (0x6B, 0x00) => StatusWord::WrongP1P2,
+            (0x6C, n) => StatusWord::WrongLe(n),
+            (0x6D, 0x00) => StatusWord::InsNotSupported,
+            (0x6E, 0x00) => StatusWord::ClaNotSupported,
+            (0x6F, 0x00) => StatusWord::NoPreciseDiagnosis,
+            _ => StatusWord::Unknown(u16::from_be_bytes([sw1, sw2])),
+        }
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Sends a command APDU to the activated PICC (see rats). The answer data is collected in
+    // `response`, which needs 2 spare bytes for the status word: 61xx is followed with
+    // GET RESPONSE until all data has arrived, 6Cxx repeats the command with the right Le.
+    pub fn transmit_apdu<'r, W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        command: &CommandApdu,
+        response: &'r mut [u8],
+    ) -> Result<ResponseApdu<'r>, RFIDError> {
+        let mut buffer = [0u8; APDU_BUFFER_SIZE];
+        let length = command.encode(&mut buffer)?;
+        let mut received = self.iso_dep_transceive(serial, &buffer[..length], response)?;
+        let (mut sw1, mut sw2) = status_bytes(response, received)?;
+
+        if sw1 == 0x6C {
+            let le = if sw2 == 0 { SHORT_LE_MAX } else { sw2 as usize };
+            let length = command.with_le(le).encode(&mut buffer)?;
+            received = self.iso_dep_transceive(serial, &buffer[..length], response)?;
+            (sw1, sw2) = status_bytes(response, received)?;
+        }
+
+        let mut length = received - 2;
+        while sw1 == 0x61 {
+            let get_response = [command.cla, INS_GET_RESPONSE, 0x00, 0x00, sw2];
+            let rest = response.get_mut(length..).ok_or(RFIDError::NoRoom)?;
+            let received = self.iso_dep_transceive(serial, &get_response, rest)?;
+            (sw1, sw2) = status_bytes(rest, received)?;
+            length += received - 2;
+        }
+        Ok(ResponseApdu { data: &response[..length], sw1, sw2 })
+    }
+
+    // Selects an application by its AID, returns the length of the FCI in `response`
+    pub fn select_aid<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        aid: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let command = CommandApdu::new(CLA_ISO, INS_SELECT, SELECT_BY_NAME, SELECT_RETURN_FCI)
+            .with_data(aid)
+            .with_le(SHORT_LE_MAX);
+        Ok(self.transmit_apdu(serial, &command, response)?.check()?.len())
+    }
+
+    // Selects an elementary or dedicated file by its 2 byte identifier
+    pub fn select_file<W: ufmt::uWrite>(&mut self, serial: &mut W, file_id: u16) -> Result<(), RFIDError> {
+        let id = file_id.to_be_bytes();
+        let command = CommandApdu::new(CLA_ISO, INS_SELECT, SELECT_BY_FILE_ID, SELECT_NO_RESPONSE).with_data(&id);
+        let mut response = [0u8; 2];
+        self.transmit_apdu(serial, &command, &mut response)?.check()?;
+        Ok(())
+    }
+
+    // Reads up to `buffer.len() - 2` bytes (at most 256) at `offset` of the selected EF,
+    // returns how many arrived
+    pub fn read_binary<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        offset: u16,
+        buffer: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        if offset > 0x7FFF || buffer.len() < 3 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let [p1, p2] = offset.to_be_bytes();
+        let le = (buffer.len() - 2).min(SHORT_LE_MAX);
+        let command = CommandApdu::new(CLA_ISO, INS_READ_BINARY, p1, p2).with_le(le);
+        Ok(self.transmit_apdu(serial, &command, buffer)?.check()?.len())
+    }
+
+    // Writes `data` (at most 255 bytes) at `offset` of the selected EF
+    pub fn update_binary<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        offset: u16,
+        data: &[u8],
+    ) -> Result<(), RFIDError> {
+        if offset > 0x7FFF || data.len() > 255 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let [p1, p2] = offset.to_be_bytes();
+        let command = CommandApdu::new(CLA_ISO, INS_UPDATE_BINARY, p1, p2).with_data(data);
+        let mut response = [0u8; 2];
+        self.transmit_apdu(serial, &command, &mut response)?.check()?;
+        Ok(())
+    }
+}
+
+// SW1 SW2 at the end of `received` bytes of `response`
+fn status_bytes(response: &[u8], received: usize) -> Result<(u8, u8), RFIDError> {
+    if received < 2 {
+        return Err(RFIDError::InvalidResponse);
+    }
+    Ok((response[received - 2], response[received - 1]))
+}
@@ -1,19 +1,103 @@
 use core::fmt::{Debug, Formatter, Result};
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum CardType {
+    MifareMini,
     Mifare1K,
     Mifare4K,
     MifareUltralight,
+    MifareUltralightC,
+    MifareUltralightEv1Mf0ul11,
+    MifareUltralightEv1Mf0ul21,
+    Ntag210,
+    Ntag212,
+    Ntag213,
+    Ntag215,
+    Ntag216,
+    NtagI2c1K,
+    NtagI2c2K,
+    NtagI2cPlus1K,
+    NtagI2cPlus2K,
     Unknown,
 }
 
+impl CardType {
+    // Classify a selected PICC by its SAK byte (NXP AN10833)
+    pub fn from_sak(sak: u8) -> CardType {
+        match sak & 0x7F {
+            0x09 => CardType::MifareMini,
+            0x08 => CardType::Mifare1K,
+            0x18 => CardType::Mifare4K,
+            0x00 => CardType::MifareUltralight,
+            _ => CardType::Unknown,
+        }
+    }
+
+    // NFC Forum Type 2 Tags (SAK 0x00), refined with GET_VERSION
+    pub fn is_type2(&self) -> bool {
+        matches!(
+            self,
+            CardType::MifareUltralight
+                | CardType::MifareUltralightC
+                | CardType::MifareUltralightEv1Mf0ul11
+                | CardType::MifareUltralightEv1Mf0ul21
+                | CardType::Ntag210
+                | CardType::Ntag212
+                | CardType::Ntag213
+                | CardType::Ntag215
+                | CardType::Ntag216
+                | CardType::NtagI2c1K
+                | CardType::NtagI2c2K
+                | CardType::NtagI2cPlus1K
+                | CardType::NtagI2cPlus2K
+        )
+    }
+}
+
 impl Debug for CardType {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
+            CardType::MifareMini => write!(f, "MifareMini"),
             CardType::Mifare1K => write!(f, "Mifare1K"),
             CardType::Mifare4K => write!(f, "Mifare4K"),
             CardType::MifareUltralight => write!(f, "MifareUltralight"),
+            CardType::MifareUltralightC => write!(f, "MifareUltralightC"),
+            CardType::MifareUltralightEv1Mf0ul11 => write!(f, "MifareUltralightEv1Mf0ul11"),
+            CardType::MifareUltralightEv1Mf0ul21 => write!(f, "MifareUltralightEv1Mf0ul21"),
+            CardType::Ntag210 => write!(f, "Ntag210"),
+            CardType::Ntag212 => write!(f, "Ntag212"),
+            CardType::Ntag213 => write!(f, "Ntag213"),
+            CardType::Ntag215 => write!(f, "Ntag215"),
+            CardType::Ntag216 => write!(f, "Ntag216"),
+            CardType::NtagI2c1K => write!(f, "NtagI2c1K"),
+            CardType::NtagI2c2K => write!(f, "NtagI2c2K"),
+            CardType::NtagI2cPlus1K => write!(f, "NtagI2cPlus1K"),
+            CardType::NtagI2cPlus2K => write!(f, "NtagI2cPlus2K"),
             CardType::Unknown => write!(f, "Unknown"),
         }
     }
 }
+
+// UID of a PICC as returned by the anticollision/select procedure
+#[derive(Clone, Copy, PartialEq)]
+pub struct Uid {
+    pub bytes: [u8; 10], // Only the first `size` bytes are valid
+    pub size: u8,        // 4, 7 or 10 bytes
+    pub sak: u8,         // SAK returned by the PICC after a successful select
+}
+
+impl Uid {
+    pub const fn new() -> Self {
+        Uid { bytes: [0u8; 10], size: 0, sak: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.size as usize]
+    }
+}
+
+impl Default for Uid {
+    fn default() -> Self {
+        Uid::new()
+    }
+}
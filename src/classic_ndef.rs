@@ -0,0 +1,322 @@
+// src/classic_ndef.rs
+// NDEF on MIFARE Classic (NFC Forum "MIFARE Classic as NFC Type MIFARE Classic Tag").
+// The sectors the MAD assigns to the NDEF AID 0xE103 form one data area, their data blocks
+// read in ascending sector order, holding the same TLV blocks as a Type 2 Tag.
+// NDEF sectors use the public key A D3F7D3F7D3F7, which can read and write the data blocks.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::{CardType, Uid};
+use crate::errors::RFIDError;
+use crate::mad::{trailer_bytes, Mad, MAD_ACCESS_BITS, MAD_AID_NDEF, MAD_KEY_A};
+use crate::mifare::{self, Key, KeyType, MF_BLOCK_SIZE};
+use crate::rfid_rc522::RfidRc522;
+use crate::type2::{TLV_NDEF, TLV_NULL, TLV_TERMINATOR};
+
+// Public key A of NDEF sectors
+pub const NFC_KEY_A: Key = Key([0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7]);
+
+// Access bits of NDEF sectors: data blocks read and written with key A or B
+pub const NDEF_ACCESS_BITS: [u8; 3] = [0x7F, 0x07, 0x88];
+
+// General purpose byte of NDEF sectors: mapping version 1.0, read and write access granted
+pub const NDEF_GPB: u8 = 0x40;
+
+// Data blocks of the NDEF sectors, in order
+fn ndef_blocks(mad: &Mad) -> impl Iterator<Item = u8> + '_ {
+    mad.sectors(MAD_AID_NDEF)
+        .flat_map(|sector| mifare::sector_first_block(sector)..mifare::sector_trailer(sector))
+}
+
+// Reads the NDEF data blocks one after the other, authenticating each sector as it is entered
+struct BlockReader<I: Iterator<Item = u8>> {
+    blocks: I,
+    sector: Option<u8>,
+    data: [u8; MF_BLOCK_SIZE],
+    pos: usize,
+}
+
+impl<I: Iterator<Item = u8>> BlockReader<I> {
+    fn new(blocks: I) -> Self {
+        BlockReader { blocks, sector: None, data: [0u8; MF_BLOCK_SIZE], pos: MF_BLOCK_SIZE }
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Copies the NDEF message of a MAD/NDEF formatted Classic card into `buffer`, returns its length
+    pub fn classic_read_ndef<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        buffer: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        // The NDEF sectors are authenticated nested in the MAD session, read_mad would end
+        // it on our side only and leave the PICC expecting encrypted frames
+        let result = self
+            .read_mad_blocks(serial, uid)
+            .and_then(|mad| self.classic_read_ndef_tlv(serial, uid, &mad, buffer));
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Replaces the NDEF message. The TLV is written with length 0 first and the real length
+    // last, so an interrupted write leaves an empty message.
+    pub fn classic_write_ndef<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        message: &[u8],
+    ) -> Result<(), RFIDError> {
+        let result = self
+            .read_mad_blocks(serial, uid)
+            .and_then(|mad| self.classic_write_ndef_tlv(serial, uid, &mad, message));
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Formats a blank Mini/1K/4K card: MAD (MAD2 on 4K) with every other sector assigned to
+    // NDEF, an empty NDEF message, and new sector trailers. `key` is the current key A of all
+    // sectors (usually the transport key), `key_b` becomes key B of every sector.
+    // The trailers are rewritten, a wrong key B locks you out of future changes.
+    pub fn classic_format_ndef<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        card_type: &CardType,
+        key: &Key,
+        key_b: &Key,
+    ) -> Result<(), RFIDError> {
+        let mut mad = Mad::new(card_type).ok_or(RFIDError::InvalidResponse)?;
+        let sector_count = mifare::sector_count(card_type);
+        for sector in 1..sector_count {
+            if !mad.is_mad_sector(sector) {
+                mad.set_aid(sector, MAD_AID_NDEF)?;
+            }
+        }
+
+        let result = self.classic_format_sectors(serial, uid, &mad, sector_count, key, key_b);
+        self.stop_crypto1(serial);
+        result
+    }
+
+    fn classic_format_sectors<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+        sector_count: u8,
+        key: &Key,
+        key_b: &Key,
+    ) -> Result<(), RFIDError> {
+        // Directory first, then each sector's data blocks followed by its trailer
+        self.write_mad_blocks(serial, uid, mad, KeyType::A, key)?;
+
+        let mut first_ndef_block = true;
+        for sector in 0..sector_count {
+            let trailer = mifare::sector_trailer(sector);
+            self.mifare_authenticate(serial, KeyType::A, trailer, key, uid)?;
+
+            if mad.is_mad_sector(sector) {
+                let trailer_data = trailer_bytes(&MAD_KEY_A, &MAD_ACCESS_BITS, mad.gpb(), key_b);
+                self.mifare_write(serial, trailer, &trailer_data)?;
+                continue;
+            }
+
+            for block in mifare::sector_first_block(sector)..trailer {
+                let mut data = [0u8; MF_BLOCK_SIZE];
+                if first_ndef_block {
+                    data[..3].copy_from_slice(&[TLV_NDEF, 0x00, TLV_TERMINATOR]);
+                    first_ndef_block = false;
+                }
+                self.mifare_write(serial, block, &data)?;
+            }
+            self.mifare_write(serial, trailer, &trailer_bytes(&NFC_KEY_A, &NDEF_ACCESS_BITS, NDEF_GPB, key_b))?;
+        }
+        Ok(())
+    }
+
+    fn classic_read_ndef_tlv<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+        buffer: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let mut reader = BlockReader::new(ndef_blocks(mad));
+        loop {
+            let tag = self.classic_next_byte(serial, uid, &mut reader)?;
+            match tag {
+                TLV_NULL => continue,
+                TLV_TERMINATOR => return Err(RFIDError::NotNdefFormatted),
+                _ => {}
+            }
+
+            let mut length = self.classic_next_byte(serial, uid, &mut reader)? as usize;
+            if length == 0xFF {
+                let high = self.classic_next_byte(serial, uid, &mut reader)?;
+                let low = self.classic_next_byte(serial, uid, &mut reader)?;
+                length = u16::from_be_bytes([high, low]) as usize;
+            }
+
+            if tag == TLV_NDEF {
+                let message = buffer.get_mut(..length).ok_or(RFIDError::NoRoom)?;
+                for byte in message.iter_mut() {
+                    *byte = self.classic_next_byte(serial, uid, &mut reader)?;
+                }
+                return Ok(length);
+            }
+            // Proprietary and unknown TLVs are skipped
+            for _ in 0..length {
+                self.classic_next_byte(serial, uid, &mut reader)?;
+            }
+        }
+    }
+
+    fn classic_next_byte<W: ufmt::uWrite, I: Iterator<Item = u8>>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        reader: &mut BlockReader<I>,
+    ) -> Result<u8, RFIDError> {
+        if reader.pos == MF_BLOCK_SIZE {
+            // Running out of NDEF blocks means the TLV claims more data than the sectors hold
+            let block = reader.blocks.next().ok_or(RFIDError::InvalidNdef)?;
+            let sector = mifare::block_sector(block);
+            if reader.sector != Some(sector) {
+                self.mifare_authenticate(serial, KeyType::A, block, &NFC_KEY_A, uid)?;
+                reader.sector = Some(sector);
+            }
+            reader.data = self.mifare_read(serial, block)?;
+            reader.pos = 0;
+        }
+        let byte = reader.data[reader.pos];
+        reader.pos += 1;
+        Ok(byte)
+    }
+
+    // Byte offset in the NDEF data area where the NDEF TLV goes: the existing NDEF TLV or
+    // Terminator, else the NULL TLVs padding the end of the area. NULL, Proprietary and other
+    // TLVs before it are kept.
+    fn classic_ndef_tlv_offset<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+    ) -> Result<usize, RFIDError> {
+        let capacity = ndef_blocks(mad).count() * MF_BLOCK_SIZE;
+        let mut reader = BlockReader::new(ndef_blocks(mad));
+        let mut offset = 0;
+        let mut trailing_nulls = None;
+        while offset < capacity {
+            let tag = self.classic_next_byte(serial, uid, &mut reader)?;
+            match tag {
+                TLV_NULL => {
+                    trailing_nulls.get_or_insert(offset);
+                    offset += 1;
+                    continue;
+                }
+                TLV_NDEF | TLV_TERMINATOR => return Ok(offset),
+                _ => trailing_nulls = None,
+            }
+
+            let mut length = self.classic_next_byte(serial, uid, &mut reader)? as usize;
+            offset += 2;
+            if length == 0xFF {
+                let high = self.classic_next_byte(serial, uid, &mut reader)?;
+                let low = self.classic_next_byte(serial, uid, &mut reader)?;
+                length = u16::from_be_bytes([high, low]) as usize;
+                offset += 2;
+            }
+            for _ in 0..length {
+                self.classic_next_byte(serial, uid, &mut reader)?;
+            }
+            offset += length;
+        }
+        trailing_nulls.ok_or(RFIDError::NoRoom)
+    }
+
+    fn classic_write_ndef_tlv<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+        message: &[u8],
+    ) -> Result<(), RFIDError> {
+        if message.len() > u16::MAX as usize - 4 {
+            return Err(RFIDError::NoRoom);
+        }
+        let length = message.len() as u16;
+        let (header, header_len): ([u8; 4], usize) = if length < 0xFF {
+            ([TLV_NDEF, length as u8, 0, 0], 2)
+        } else {
+            let [high, low] = length.to_be_bytes();
+            ([TLV_NDEF, 0xFF, high, low], 4)
+        };
+        let offset = self.classic_ndef_tlv_offset(serial, uid, mad)?;
+        let capacity = ndef_blocks(mad).count() * MF_BLOCK_SIZE - offset;
+        let needed = header_len + message.len();
+        if needed > capacity {
+            return Err(RFIDError::NoRoom);
+        }
+        let terminator = if needed < capacity { Some(TLV_TERMINATOR) } else { None };
+
+        // TLV with length 0, then the message and the Terminator, padded to whole blocks
+        let mut empty = header;
+        if header_len == 2 {
+            empty[1] = 0;
+        } else {
+            empty[2..4].fill(0);
+        }
+        let mut bytes = empty[..header_len]
+            .iter()
+            .copied()
+            .chain(message.iter().copied())
+            .chain(terminator)
+            .peekable();
+        // The TLV header can straddle the first two blocks
+        let mut header_blocks: [Option<(u8, [u8; MF_BLOCK_SIZE])>; 2] = [None, None];
+        let mut begin = offset % MF_BLOCK_SIZE;
+        let mut sector = None;
+        for (i, block) in ndef_blocks(mad).skip(offset / MF_BLOCK_SIZE).enumerate() {
+            if bytes.peek().is_none() {
+                break;
+            }
+            if sector != Some(mifare::block_sector(block)) {
+                self.mifare_authenticate(serial, KeyType::A, block, &NFC_KEY_A, uid)?;
+                sector = Some(mifare::block_sector(block));
+            }
+            // The TLVs before the NDEF TLV stay as they are
+            let mut data = if begin > 0 { self.mifare_read(serial, block)? } else { [0u8; MF_BLOCK_SIZE] };
+            for (byte, value) in data[begin..].iter_mut().zip(&mut bytes) {
+                *byte = value;
+            }
+            begin = 0;
+            self.mifare_write(serial, block, &data)?;
+            if let Some(header_block) = header_blocks.get_mut(i) {
+                *header_block = Some((block, data));
+            }
+        }
+
+        // Commit the length, in the one or two blocks the TLV header covers
+        let header_start = offset % MF_BLOCK_SIZE;
+        for (i, &value) in header[..header_len].iter().enumerate() {
+            let pos = header_start + i;
+            let (_, data) = header_blocks[pos / MF_BLOCK_SIZE].as_mut().ok_or(RFIDError::NoRoom)?;
+            data[pos % MF_BLOCK_SIZE] = value;
+        }
+        let header_block_count = (header_start + header_len).div_ceil(MF_BLOCK_SIZE);
+        for header_block in header_blocks.iter().take(header_block_count) {
+            let (block, data) = header_block.ok_or(RFIDError::NoRoom)?;
+            if sector != Some(mifare::block_sector(block)) {
+                self.mifare_authenticate(serial, KeyType::A, block, &NFC_KEY_A, uid)?;
+                sector = Some(mifare::block_sector(block));
+            }
+            self.mifare_write(serial, block, &data)?;
+        }
+        Ok(())
+    }
+}
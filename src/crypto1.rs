@@ -0,0 +1,258 @@
+// src/crypto1.rs
+// Software implementation of the MIFARE Classic Crypto1 stream cipher.
+// The MFRC522 runs Crypto1 in hardware (MFAuthent); this is for host side tools and tests.
+// The 48 bit LFSR is kept as its odd and even bits, like the crapto1 reference implementation.
+
+use crate::mifare::Key;
+
+const LF_POLY_ODD: u32 = 0x29CE5C;
+const LF_POLY_EVEN: u32 = 0x870804;
+
+fn bit(x: u32, n: u32) -> u32 {
+    (x >> n) & 1
+}
+
+// Bit n of a word whose bytes are sent in big endian order, LSB of each byte first
+fn be_bit(x: u32, n: u32) -> u32 {
+    bit(x, n ^ 24)
+}
+
+fn parity(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x ^= x >> 8;
+    x ^= x >> 4;
+    bit(0x6996, x & 0xF)
+}
+
+// Odd parity bit as sent after every byte of an ISO 14443-3 frame
+pub fn odd_parity(byte: u8) -> u8 {
+    (parity(byte as u32) ^ 1) as u8
+}
+
+// Non-linear filter over the odd LFSR bits, producing one keystream bit
+pub fn filter(x: u32) -> u8 {
+    let mut f;
+    f = (0xF22C0 >> (x & 0xF)) & 16;
+    f |= (0x6C9C0 >> ((x >> 4) & 0xF)) & 8;
+    f |= (0x3C8B0 >> ((x >> 8) & 0xF)) & 4;
+    f |= (0x1E458 >> ((x >> 12) & 0xF)) & 2;
+    f |= (0x0D938 >> ((x >> 16) & 0xF)) & 1;
+    bit(0xEC57E80A, f) as u8
+}
+
+// Advances the 16 bit tag nonce PRNG `n` steps. suc^64(nT) is the reader answer aR, suc^96(nT) the tag answer aT.
+pub fn prng_successor(x: u32, n: u32) -> u32 {
+    let mut x = x.swap_bytes();
+    for _ in 0..n {
+        x = (x >> 1) | (((x >> 16) ^ (x >> 18) ^ (x >> 19) ^ (x >> 21)) << 31);
+    }
+    x.swap_bytes()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Crypto1 {
+    odd: u32,
+    even: u32,
+}
+
+impl Crypto1 {
+    pub fn new(key: &Key) -> Self {
+        let key = key.0.iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+        let mut state = Crypto1 { odd: 0, even: 0 };
+        for i in (1..48).rev().step_by(2) {
+            state.odd = (state.odd << 1) | ((key >> ((i - 1) ^ 7)) & 1) as u32;
+            state.even = (state.even << 1) | ((key >> (i ^ 7)) & 1) as u32;
+        }
+        state
+    }
+
+    // Key recovered from the current LFSR state (only equal to the key before any clocking)
+    pub fn lfsr(&self) -> u64 {
+        let mut lfsr = 0u64;
+        for i in (0..24).rev() {
+            lfsr = (lfsr << 1) | bit(self.odd, i ^ 3) as u64;
+            lfsr = (lfsr << 1) | bit(self.even, i ^ 3) as u64;
+        }
+        lfsr
+    }
+
+    // Keystream bit that will be output by the next clock
+    pub fn peek(&self) -> u8 {
+        filter(self.odd)
+    }
+
+    // Clocks the LFSR once feeding `input` into it. When `is_encrypted` is set, `input` is
+    // ciphertext and the keystream bit is added to it first (tag side of the reader nonce).
+    pub fn bit(&mut self, input: u8, is_encrypted: bool) -> u8 {
+        let ret = filter(self.odd);
+
+        let mut feedin = if is_encrypted { ret as u32 } else { 0 };
+        feedin ^= (input != 0) as u32;
+        feedin ^= LF_POLY_ODD & self.odd;
+        feedin ^= LF_POLY_EVEN & self.even;
+        self.even = (self.even << 1) | parity(feedin);
+
+        core::mem::swap(&mut self.odd, &mut self.even);
+        ret
+    }
+
+    // Eight clocks, bits are shifted in LSB first as on air
+    pub fn byte(&mut self, input: u8, is_encrypted: bool) -> u8 {
+        let mut ret = 0;
+        for i in 0..8 {
+            ret |= self.bit((input >> i) & 1, is_encrypted) << i;
+        }
+        ret
+    }
+
+    // 32 clocks over a big endian word (nonces and UIDs)
+    pub fn word(&mut self, input: u32, is_encrypted: bool) -> u32 {
+        let mut ret = 0;
+        for i in 0..32 {
+            ret |= (self.bit(be_bit(input, i) as u8, is_encrypted) as u32) << (i ^ 24);
+        }
+        ret
+    }
+
+    // Encrypts a frame in place and returns the encrypted parity bits in `parity` (one per byte)
+    pub fn encrypt(&mut self, data: &mut [u8], parity: &mut [u8]) {
+        for (byte, par) in data.iter_mut().zip(parity.iter_mut()) {
+            let plain = *byte;
+            *byte = plain ^ self.byte(0x00, false);
+            *par = odd_parity(plain) ^ self.peek();
+        }
+    }
+
+    // Decrypts a frame in place. Parity bits of the ciphertext are not checked.
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte ^= self.byte(0x00, false);
+        }
+    }
+}
+
+// Reader side of the three pass authentication:
+//   tag -> nT, reader -> {nR}{aR}, tag -> {aT}
+pub struct ReaderAuth {
+    cipher: Crypto1,
+    nt: u32,
+}
+
+impl ReaderAuth {
+    // `uid` is the (last) 4 UID bytes and `nt` the plain tag nonce, both big endian
+    pub fn new(key: &Key, uid: u32, nt: u32) -> Self {
+        let mut cipher = Crypto1::new(key);
+        cipher.word(uid ^ nt, false);
+        ReaderAuth { cipher, nt }
+    }
+
+    // Returns the encrypted reader nonce {nR} and reader answer {aR}
+    pub fn answer(&mut self, nr: u32) -> (u32, u32) {
+        let nr_enc = nr ^ self.cipher.word(nr, false);
+        let ar_enc = prng_successor(self.nt, 64) ^ self.cipher.word(0, false);
+        (nr_enc, ar_enc)
+    }
+
+    // Checks the encrypted tag answer {aT}; on success the cipher is ready for the session
+    pub fn verify(&mut self, at_enc: u32) -> bool {
+        at_enc ^ self.cipher.word(0, false) == prng_successor(self.nt, 96)
+    }
+
+    pub fn into_cipher(self) -> Crypto1 {
+        self.cipher
+    }
+}
+
+// Tag side of the three pass authentication
+pub struct TagAuth {
+    cipher: Crypto1,
+    nt: u32,
+}
+
+impl TagAuth {
+    pub fn new(key: &Key, uid: u32, nt: u32) -> Self {
+        let mut cipher = Crypto1::new(key);
+        cipher.word(uid ^ nt, false);
+        TagAuth { cipher, nt }
+    }
+
+    // Checks {nR}{aR} from the reader and returns the encrypted tag answer {aT},
+    // or None if the reader used a different key
+    pub fn verify(&mut self, nr_enc: u32, ar_enc: u32) -> Option<u32> {
+        self.cipher.word(nr_enc, true);
+        if ar_enc ^ self.cipher.word(0, false) != prng_successor(self.nt, 64) {
+            return None;
+        }
+        Some(prng_successor(self.nt, 96) ^ self.cipher.word(0, false))
+    }
+
+    pub fn into_cipher(self) -> Crypto1 {
+        self.cipher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Authentication from the public mfkey64 sample trace
+    const KEY: Key = Key([0xFF; 6]);
+    const UID: u32 = 0x9C59_9B32;
+    const NT: u32 = 0x82A4_166C;
+    const NR_ENC: u32 = 0xA1E4_58CE;
+    const AR_ENC: u32 = 0x6EEA_41E0;
+    const AT_ENC: u32 = 0x5CAD_F439;
+
+    #[test]
+    fn key_loads_into_lfsr() {
+        let cipher = Crypto1::new(&Key([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]));
+        assert_eq!(cipher.lfsr(), 0xA0A1_A2A3_A4A5);
+    }
+
+    #[test]
+    fn tag_auth_known_answer() {
+        let mut tag = TagAuth::new(&KEY, UID, NT);
+        assert_eq!(tag.verify(NR_ENC, AR_ENC), Some(AT_ENC));
+    }
+
+    #[test]
+    fn tag_auth_rejects_wrong_key() {
+        let mut tag = TagAuth::new(&Key([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]), UID, NT);
+        assert_eq!(tag.verify(NR_ENC, AR_ENC), None);
+    }
+
+    #[test]
+    fn reader_auth_known_answer() {
+        // Plain nR of the trace, from the keystream after uid ^ nT
+        let mut cipher = Crypto1::new(&KEY);
+        cipher.word(UID ^ NT, false);
+        let nr = NR_ENC ^ cipher.word(NR_ENC, true);
+
+        let mut reader = ReaderAuth::new(&KEY, UID, NT);
+        assert_eq!(reader.answer(nr), (NR_ENC, AR_ENC));
+        assert!(reader.verify(AT_ENC));
+    }
+
+    #[test]
+    fn reader_tag_round_trip() {
+        let key = Key([0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD]);
+        let (uid, nt, nr) = (0x0102_0304, 0x0123_4567, 0x89AB_CDEF);
+        let mut reader = ReaderAuth::new(&key, uid, nt);
+        let mut tag = TagAuth::new(&key, uid, nt);
+
+        let (nr_enc, ar_enc) = reader.answer(nr);
+        let at_enc = tag.verify(nr_enc, ar_enc).expect("tag rejects the reader");
+        assert!(reader.verify(at_enc));
+
+        // Both ciphers continue in step for the session
+        let mut reader = reader.into_cipher();
+        let mut tag = tag.into_cipher();
+        let plain = [0x30, 0x04, 0x26, 0xEE];
+        let mut frame = plain;
+        let mut parity = [0u8; 4];
+        reader.encrypt(&mut frame, &mut parity);
+        assert_ne!(frame, plain);
+        tag.decrypt(&mut frame);
+        assert_eq!(frame, plain);
+    }
+}
@@ -0,0 +1,26 @@
+// src/delay.rs
+// Busy waits of the driver: arduino-hal on the AVR target, thread sleeps on hosts with the
+// std feature (dump_formats and desktop tools)
+
+#[cfg(target_arch = "avr")]
+pub(crate) fn delay_ms(ms: u16) {
+    arduino_hal::delay_ms(ms);
+}
+
+#[cfg(target_arch = "avr")]
+pub(crate) fn delay_us(us: u32) {
+    arduino_hal::delay_us(us);
+}
+
+#[cfg(all(not(target_arch = "avr"), feature = "std"))]
+pub(crate) fn delay_ms(ms: u16) {
+    std::thread::sleep(std::time::Duration::from_millis(ms as u64));
+}
+
+#[cfg(all(not(target_arch = "avr"), feature = "std"))]
+pub(crate) fn delay_us(us: u32) {
+    std::thread::sleep(std::time::Duration::from_micros(us as u64));
+}
+
+#[cfg(all(not(target_arch = "avr"), not(feature = "std")))]
+compile_error!("rfid_rc522 builds for the AVR target, or for a host with the std feature");
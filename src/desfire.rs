@@ -0,0 +1,661 @@
+// src/desfire.rs
+// MIFARE DESFire commands over ISO-DEP (see rats). Commands are sent either natively
+// (command code, parameters / status code, data) or wrapped in ISO/IEC 7816-4 APDUs with
+// CLA 0x90, where the status comes back as SW1 0x91 and SW2 the native status code.
+// Long commands and answers are split into frames continued with ADDITIONAL_FRAME.
+// After authenticate, commands are MACed or enciphered as desfire_crypto describes.
+// Multi-byte values (AIDs, offsets, lengths, value amounts) are little endian.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::errors::RFIDError;
+use crate::desfire_crypto::{AuthMode, DesfireAuth, DesfireKey, DesfireSession, Protection, DF_SECURE_OVERHEAD};
+use crate::iso14443_4::MAX_FRAME_SIZE;
+use crate::rfid_rc522::RfidRc522;
+
+// Native command codes
+pub const DF_GET_VERSION: u8 = 0x60;
+pub const DF_GET_APPLICATION_IDS: u8 = 0x6A;
+pub const DF_SELECT_APPLICATION: u8 = 0x5A;
+pub const DF_CREATE_APPLICATION: u8 = 0xCA;
+pub const DF_GET_FILE_IDS: u8 = 0x6F;
+pub const DF_GET_FILE_SETTINGS: u8 = 0xF5;
+pub const DF_CREATE_STD_DATA_FILE: u8 = 0xCD;
+pub const DF_CREATE_BACKUP_DATA_FILE: u8 = 0xCB;
+pub const DF_CREATE_VALUE_FILE: u8 = 0xCC;
+pub const DF_CREATE_LINEAR_RECORD_FILE: u8 = 0xC1;
+pub const DF_CREATE_CYCLIC_RECORD_FILE: u8 = 0xC0;
+pub const DF_READ_DATA: u8 = 0xBD;
+pub const DF_WRITE_DATA: u8 = 0x3D;
+pub const DF_GET_VALUE: u8 = 0x6C;
+pub const DF_CREDIT: u8 = 0x0C;
+pub const DF_DEBIT: u8 = 0xDC;
+pub const DF_READ_RECORDS: u8 = 0xBB;
+pub const DF_COMMIT_TRANSACTION: u8 = 0xC7;
+pub const DF_ABORT_TRANSACTION: u8 = 0xA7;
+pub const DF_CHANGE_KEY: u8 = 0xC4;
+pub const DF_ADDITIONAL_FRAME: u8 = 0xAF;
+
+// Status codes
+pub const DF_OPERATION_OK: u8 = 0x00;
+pub const DF_NO_CHANGES: u8 = 0x0C;
+pub const DF_AUTHENTICATION_ERROR: u8 = 0xAE;
+
+// CLA of ISO wrapped commands, SW1 of their answers
+const DF_ISO_CLA: u8 = 0x90;
+const DF_ISO_SW1: u8 = 0x91;
+
+// The PICC application, selected after activation
+pub const DF_PICC_AID: u32 = 0x000000;
+
+// Command parameters and data sent per frame
+const FRAME_PAYLOAD: usize = 52;
+
+// Data written per command in a secure session, so the protected parameters fit a fixed buffer
+const SECURE_CHUNK: usize = 128;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Framing {
+    Native,
+    IsoWrapped,
+}
+
+// Meaning of a DESFire status code
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DesfireStatus {
+    OutOfEeprom,          // 0x0E
+    IllegalCommand,       // 0x1C
+    IntegrityError,       // 0x1E - CRC or MAC mismatch, padding
+    NoSuchKey,            // 0x40
+    LengthError,          // 0x7E
+    PermissionDenied,     // 0x9D
+    ParameterError,       // 0x9E
+    ApplicationNotFound,  // 0xA0
+    ApplIntegrityError,   // 0xA1
+    AuthenticationError,  // 0xAE
+    BoundaryError,        // 0xBE - e.g. reading beyond the file or a value out of limits
+    PiccIntegrityError,   // 0xC1
+    CommandAborted,       // 0xCA
+    PiccDisabled,         // 0xCD
+    CountError,           // 0xCE - too many applications or files
+    DuplicateError,       // 0xDE
+    EepromError,          // 0xEE
+    FileNotFound,         // 0xF0
+    FileIntegrityError,   // 0xF1
+    Unknown(u8),
+}
+
+impl DesfireStatus {
+    pub fn from_code(code: u8) -> DesfireStatus {
+        match code {
+            0x0E => DesfireStatus::OutOfEeprom,
+            0x1C => DesfireStatus::IllegalCommand,
+            0x1E => DesfireStatus::IntegrityError,
+            0x40 => DesfireStatus::NoSuchKey,
+            0x7E => DesfireStatus::LengthError,
+            0x9D => DesfireStatus::PermissionDenied,
+            0x9E => DesfireStatus::ParameterError,
+            0xA0 => DesfireStatus::ApplicationNotFound,
+            0xA1 => DesfireStatus::ApplIntegrityError,
+            0xAE => DesfireStatus::AuthenticationError,
+            0xBE => DesfireStatus::BoundaryError,
+            0xC1 => DesfireStatus::PiccIntegrityError,
+            0xCA => DesfireStatus::CommandAborted,
+            0xCD => DesfireStatus::PiccDisabled,
+            0xCE => DesfireStatus::CountError,
+            0xDE => DesfireStatus::DuplicateError,
+            0xEE => DesfireStatus::EepromError,
+            0xF0 => DesfireStatus::FileNotFound,
+            0xF1 => DesfireStatus::FileIntegrityError,
+            other => DesfireStatus::Unknown(other),
+        }
+    }
+}
+
+// One half of the GetVersion answer
+#[derive(Clone, Copy, PartialEq)]
+pub struct VersionInfo {
+    pub vendor_id: u8, // 0x04 for NXP
+    pub product_type: u8,
+    pub subtype: u8,
+    pub major: u8,
+    pub minor: u8,
+    pub storage_size: u8,
+    pub protocol: u8, // 0x05 for ISO/IEC 14443-2 and -3
+}
+
+impl VersionInfo {
+    fn parse(data: &[u8]) -> Self {
+        VersionInfo {
+            vendor_id: data[0],
+            product_type: data[1],
+            subtype: data[2],
+            major: data[3],
+            minor: data[4],
+            storage_size: data[5],
+            protocol: data[6],
+        }
+    }
+
+    // 2^n bytes for storage size 2n, between 2^n and 2^(n+1) if the lowest bit is set.
+    // None for sizes of 2^32 bytes and more.
+    pub fn storage_bytes(&self) -> Option<u32> {
+        1u32.checked_shl((self.storage_size >> 1) as u32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct DesfireVersion {
+    pub hardware: VersionInfo,
+    pub software: VersionInfo,
+    pub uid: [u8; 7],
+    pub batch: [u8; 5],
+    pub production_week: u8, // BCD
+    pub production_year: u8, // BCD
+}
+
+impl DesfireVersion {
+    pub fn parse(data: &[u8]) -> Result<Self, RFIDError> {
+        if data.len() < 28 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let mut uid = [0u8; 7];
+        uid.copy_from_slice(&data[14..21]);
+        let mut batch = [0u8; 5];
+        batch.copy_from_slice(&data[21..26]);
+        Ok(DesfireVersion {
+            hardware: VersionInfo::parse(&data[..7]),
+            software: VersionInfo::parse(&data[7..14]),
+            uid,
+            batch,
+            production_week: data[26],
+            production_year: data[27],
+        })
+    }
+}
+
+// Communication settings of a file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CommMode {
+    Plain,
+    Mac,
+    Enciphered,
+}
+
+impl CommMode {
+    pub fn from_bits(bits: u8) -> CommMode {
+        match bits & 0x03 {
+            0x01 => CommMode::Mac,
+            0x03 => CommMode::Enciphered,
+            _ => CommMode::Plain,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match self {
+            CommMode::Plain => 0x00,
+            CommMode::Mac => 0x01,
+            CommMode::Enciphered => 0x03,
+        }
+    }
+}
+
+// Key number (0-13) needed for each kind of access, 0xE for free access, 0xF for never
+#[derive(Clone, Copy, PartialEq)]
+pub struct AccessRights {
+    pub read: u8,
+    pub write: u8,
+    pub read_write: u8,
+    pub change: u8,
+}
+
+impl AccessRights {
+    pub const FREE: u8 = 0x0E;
+    pub const NEVER: u8 = 0x0F;
+
+    pub fn from_bits(bits: u16) -> Self {
+        AccessRights {
+            read: (bits >> 12) as u8 & 0x0F,
+            write: (bits >> 8) as u8 & 0x0F,
+            read_write: (bits >> 4) as u8 & 0x0F,
+            change: bits as u8 & 0x0F,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        (self.read as u16 & 0x0F) << 12
+            | (self.write as u16 & 0x0F) << 8
+            | (self.read_write as u16 & 0x0F) << 4
+            | (self.change as u16 & 0x0F)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileKind {
+    StandardData { size: u32 },
+    BackupData { size: u32 },
+    // `value` is the initial value when creating, the limited credit value when read back
+    Value { lower_limit: i32, upper_limit: i32, value: i32, limited_credit: bool },
+    LinearRecord { record_size: u32, max_records: u32, current_records: u32 },
+    CyclicRecord { record_size: u32, max_records: u32, current_records: u32 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileSettings {
+    pub comm_mode: CommMode,
+    pub access_rights: AccessRights,
+    pub kind: FileKind,
+}
+
+impl FileSettings {
+    // Parses the GetFileSettings answer
+    pub fn parse(data: &[u8]) -> Result<Self, RFIDError> {
+        let field = |index: usize| -> Result<u32, RFIDError> {
+            let bytes = data.get(4 + 3 * index..7 + 3 * index).ok_or(RFIDError::InvalidResponse)?;
+            Ok(le24(bytes))
+        };
+        let value = |index: usize| -> Result<i32, RFIDError> {
+            let bytes = data.get(4 + 4 * index..8 + 4 * index).ok_or(RFIDError::InvalidResponse)?;
+            Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        if data.len() < 4 {
+            return Err(RFIDError::InvalidResponse);
+        }
+
+        let kind = match data[0] {
+            0x00 => FileKind::StandardData { size: field(0)? },
+            0x01 => FileKind::BackupData { size: field(0)? },
+            0x02 => FileKind::Value {
+                lower_limit: value(0)?,
+                upper_limit: value(1)?,
+                value: value(2)?,
+                limited_credit: *data.get(16).ok_or(RFIDError::InvalidResponse)? & 0x01 != 0,
+            },
+            0x03 => FileKind::LinearRecord { record_size: field(0)?, max_records: field(1)?, current_records: field(2)? },
+            0x04 => FileKind::CyclicRecord { record_size: field(0)?, max_records: field(1)?, current_records: field(2)? },
+            _ => return Err(RFIDError::InvalidResponse),
+        };
+        Ok(FileSettings {
+            comm_mode: CommMode::from_bits(data[1]),
+            access_rights: AccessRights::from_bits(u16::from_le_bytes([data[2], data[3]])),
+            kind,
+        })
+    }
+}
+
+// Key type of a new application, combined with the key count
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DesfireKeyType {
+    Des, // DES and 2K3DES
+    Tdes3k,
+    Aes,
+}
+
+impl DesfireKeyType {
+    pub fn bits(&self) -> u8 {
+        match self {
+            DesfireKeyType::Des => 0x00,
+            DesfireKeyType::Tdes3k => 0x40,
+            DesfireKeyType::Aes => 0x80,
+        }
+    }
+}
+
+// A DESFire card activated with RATS
+pub struct Desfire<'a, SPI, CS, W> {
+    rfid: &'a mut RfidRc522<SPI, CS>,
+    serial: &'a mut W,
+    framing: Framing,
+    session: Option<DesfireSession>,
+}
+
+impl<'a, SPI, CS, W> Desfire<'a, SPI, CS, W> {
+    pub fn new(rfid: &'a mut RfidRc522<SPI, CS>, serial: &'a mut W, framing: Framing) -> Self {
+        Desfire { rfid, serial, framing, session: None }
+    }
+
+    // The secure messaging session of the last authenticate, if it is still valid
+    pub fn session(&self) -> Option<&DesfireSession> {
+        self.session.as_ref()
+    }
+}
+
+impl<SPI, CS, W> Desfire<'_, SPI, CS, W>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+    W: ufmt::uWrite,
+{
+    pub fn get_version(&mut self) -> Result<DesfireVersion, RFIDError> {
+        let mut response = [0u8; 28 + DF_SECURE_OVERHEAD];
+        let length = self.command(DF_GET_VERSION, &[], &[], Protection::Command, &mut response)?;
+        DesfireVersion::parse(&response[..length])
+    }
+
+    // Copies the AIDs of all applications into `aids`, returns how many there are
+    pub fn get_application_ids(&mut self, aids: &mut [u32]) -> Result<usize, RFIDError> {
+        let mut response = [0u8; 28 * 3 + DF_SECURE_OVERHEAD];
+        let length = self.command(DF_GET_APPLICATION_IDS, &[], &[], Protection::Command, &mut response)?;
+        let count = length / 3;
+        let aids = aids.get_mut(..count).ok_or(RFIDError::NoRoom)?;
+        for (aid, bytes) in aids.iter_mut().zip(response.chunks_exact(3)) {
+            *aid = le24(bytes);
+        }
+        Ok(count)
+    }
+
+    // Selecting an application ends the authenticated session
+    pub fn select_application(&mut self, aid: u32) -> Result<(), RFIDError> {
+        self.session = None;
+        self.transceive(DF_SELECT_APPLICATION, &aid.to_le_bytes()[..3], &[], &mut [])?;
+        Ok(())
+    }
+
+    // Mutual authentication with key `key_no` of the selected application. `rnd_a` must come
+    // from a good random source. On success the following commands use secure messaging.
+    pub fn authenticate(&mut self, mode: AuthMode, key_no: u8, key: &DesfireKey, rnd_a: &[u8; 16]) -> Result<(), RFIDError> {
+        self.session = None;
+        let mut auth = DesfireAuth::new(mode, key_no, key, rnd_a)?;
+
+        // EV2First sends an empty PCDcap2 (LenCap 0)
+        let ev2_header = [key_no, 0x00];
+        let legacy_header = [key_no];
+        let header: &[u8] = if mode == AuthMode::Ev2First { &ev2_header } else { &legacy_header };
+        let mut answer = [0u8; MAX_FRAME_SIZE];
+        let (status, length) = self.exchange(mode.command(), header, &mut answer)?;
+        if status != DF_ADDITIONAL_FRAME {
+            return Err(status_error(status));
+        }
+        let (token, token_len) = auth.answer(&answer[..length])?;
+
+        let (status, length) = self.exchange(DF_ADDITIONAL_FRAME, &token[..token_len], &mut answer)?;
+        if status != DF_OPERATION_OK {
+            return Err(match status {
+                DF_AUTHENTICATION_ERROR => RFIDError::AuthenticationFailed,
+                other => status_error(other),
+            });
+        }
+        self.session = Some(auth.finish(&answer[..length])?);
+        Ok(())
+    }
+
+    // Changes key `key_no` of the selected application (with the key type bits for the PICC
+    // master key) to `new_key`. `old_key` is only needed when changing another key than the
+    // authenticated one; changing the authenticated key ends the session.
+    pub fn change_key(&mut self, key_no: u8, old_key: &DesfireKey, new_key: &DesfireKey, version: u8) -> Result<(), RFIDError> {
+        let mut session = self.session.take().ok_or(RFIDError::AuthenticationFailed)?;
+        let mut payload = [0u8; 64];
+        let length = session.change_key_command(DF_CHANGE_KEY, key_no, old_key, new_key, version, &mut payload)?;
+
+        let mut response = [0u8; DF_SECURE_OVERHEAD];
+        let (received, status) = self.transceive_status(DF_CHANGE_KEY, &payload[..length], &[], &mut response)?;
+        if key_no & 0x0F != session.key_no & 0x0F {
+            session.unwrap_response(status, &mut response, received, Protection::Command, true)?;
+            self.session = Some(session);
+        }
+        Ok(())
+    }
+
+    // Creates an application with `key_count` keys (1-14) of `key_type`
+    pub fn create_application(
+        &mut self,
+        aid: u32,
+        key_settings: u8,
+        key_count: u8,
+        key_type: DesfireKeyType,
+    ) -> Result<(), RFIDError> {
+        let [a0, a1, a2, _] = aid.to_le_bytes();
+        let header = [a0, a1, a2, key_settings, key_count | key_type.bits()];
+        self.command(DF_CREATE_APPLICATION, &header, &[], Protection::Command, &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    // Copies the file numbers of the selected application into `files`, returns how many
+    pub fn get_file_ids(&mut self, files: &mut [u8]) -> Result<usize, RFIDError> {
+        let mut response = [0u8; 32 + DF_SECURE_OVERHEAD];
+        let length = self.command(DF_GET_FILE_IDS, &[], &[], Protection::Command, &mut response)?;
+        files.get_mut(..length).ok_or(RFIDError::NoRoom)?.copy_from_slice(&response[..length]);
+        Ok(length)
+    }
+
+    pub fn get_file_settings(&mut self, file_no: u8) -> Result<FileSettings, RFIDError> {
+        let mut response = [0u8; 32 + DF_SECURE_OVERHEAD];
+        let length = self.command(DF_GET_FILE_SETTINGS, &[file_no], &[], Protection::Command, &mut response)?;
+        FileSettings::parse(&response[..length])
+    }
+
+    // Creates a file with the command matching `settings.kind`
+    pub fn create_file(&mut self, file_no: u8, settings: &FileSettings) -> Result<(), RFIDError> {
+        let mut header = [0u8; 17];
+        header[0] = file_no;
+        header[1] = settings.comm_mode.bits();
+        header[2..4].copy_from_slice(&settings.access_rights.bits().to_le_bytes());
+        let (command, length) = match settings.kind {
+            FileKind::StandardData { size } | FileKind::BackupData { size } => {
+                header[4..7].copy_from_slice(&size.to_le_bytes()[..3]);
+                let command = match settings.kind {
+                    FileKind::StandardData { .. } => DF_CREATE_STD_DATA_FILE,
+                    _ => DF_CREATE_BACKUP_DATA_FILE,
+                };
+                (command, 7)
+            }
+            FileKind::Value { lower_limit, upper_limit, value, limited_credit } => {
+                header[4..8].copy_from_slice(&lower_limit.to_le_bytes());
+                header[8..12].copy_from_slice(&upper_limit.to_le_bytes());
+                header[12..16].copy_from_slice(&value.to_le_bytes());
+                header[16] = limited_credit as u8;
+                (DF_CREATE_VALUE_FILE, 17)
+            }
+            FileKind::LinearRecord { record_size, max_records, .. } | FileKind::CyclicRecord { record_size, max_records, .. } => {
+                header[4..7].copy_from_slice(&record_size.to_le_bytes()[..3]);
+                header[7..10].copy_from_slice(&max_records.to_le_bytes()[..3]);
+                let command = match settings.kind {
+                    FileKind::LinearRecord { .. } => DF_CREATE_LINEAR_RECORD_FILE,
+                    _ => DF_CREATE_CYCLIC_RECORD_FILE,
+                };
+                (command, 10)
+            }
+        };
+        self.command(command, &header[..length], &[], Protection::Command, &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    // Reads `length` bytes at `offset` of a data file (0 for all from `offset` to the end),
+    // returns how many arrived. `comm` is the communication mode of the file; MACed and
+    // enciphered answers need DF_SECURE_OVERHEAD spare bytes in `buffer`.
+    pub fn read_data(&mut self, file_no: u8, offset: u32, length: u32, comm: CommMode, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+        let header = offset_length(file_no, offset, length);
+        self.command(DF_READ_DATA, &header, &[], Protection::File(comm), buffer)
+    }
+
+    pub fn write_data(&mut self, file_no: u8, offset: u32, data: &[u8], comm: CommMode) -> Result<(), RFIDError> {
+        let mut offset = offset;
+        for chunk in data.chunks(SECURE_CHUNK) {
+            let header = offset_length(file_no, offset, chunk.len() as u32);
+            self.command(DF_WRITE_DATA, &header, chunk, Protection::File(comm), &mut [0u8; DF_SECURE_OVERHEAD])?;
+            offset += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    pub fn get_value(&mut self, file_no: u8, comm: CommMode) -> Result<i32, RFIDError> {
+        let mut response = [0u8; 4 + DF_SECURE_OVERHEAD];
+        let length = self.command(DF_GET_VALUE, &[file_no], &[], Protection::File(comm), &mut response)?;
+        if length != 4 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        Ok(i32::from_le_bytes([response[0], response[1], response[2], response[3]]))
+    }
+
+    // Credit and Debit take effect with commit_transaction
+    pub fn credit(&mut self, file_no: u8, amount: i32, comm: CommMode) -> Result<(), RFIDError> {
+        self.command(DF_CREDIT, &[file_no], &amount.to_le_bytes(), Protection::File(comm), &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    pub fn debit(&mut self, file_no: u8, amount: i32, comm: CommMode) -> Result<(), RFIDError> {
+        self.command(DF_DEBIT, &[file_no], &amount.to_le_bytes(), Protection::File(comm), &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    // Reads `count` records starting `offset` records from the newest (0 for all),
+    // oldest first. Returns the number of bytes in `buffer`, see read_data for `comm`.
+    pub fn read_records(&mut self, file_no: u8, offset: u32, count: u32, comm: CommMode, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+        let header = offset_length(file_no, offset, count);
+        self.command(DF_READ_RECORDS, &header, &[], Protection::File(comm), buffer)
+    }
+
+    pub fn commit_transaction(&mut self) -> Result<(), RFIDError> {
+        self.command(DF_COMMIT_TRANSACTION, &[], &[], Protection::Command, &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    pub fn abort_transaction(&mut self) -> Result<(), RFIDError> {
+        self.command(DF_ABORT_TRANSACTION, &[], &[], Protection::Command, &mut [0u8; DF_SECURE_OVERHEAD])?;
+        Ok(())
+    }
+
+    // Sends a command with the protection of the current session, if there is one. The PICC
+    // drops the session on any error, so do we.
+    pub(crate) fn command(
+        &mut self,
+        command: u8,
+        header: &[u8],
+        data: &[u8],
+        protection: Protection,
+        response: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let Some(mut session) = self.session else {
+            return self.transceive(command, header, data, response);
+        };
+        let mut payload = [0u8; 8 + SECURE_CHUNK + 2 * DF_SECURE_OVERHEAD];
+        let length = session.wrap_command(command, header, data, protection, &mut payload)?;
+        self.session = None;
+        let (received, status) = self.transceive_status(command, &payload[..length], &[], response)?;
+        let length = session.unwrap_response(status, response, received, protection, !data.is_empty())?;
+        self.session = Some(session);
+        Ok(length)
+    }
+
+    // Sends `command` with `header` and `data` as parameters, split into frames, and collects
+    // the answer data of all frames in `response`. Returns its length.
+    pub fn transceive(&mut self, command: u8, header: &[u8], data: &[u8], response: &mut [u8]) -> Result<usize, RFIDError> {
+        let (length, _) = self.transceive_status(command, header, data, response)?;
+        Ok(length)
+    }
+
+    // transceive, also returning the final status (OPERATION_OK or NO_CHANGES) that EV1
+    // secure messaging covers with its MAC
+    fn transceive_status(&mut self, command: u8, header: &[u8], data: &[u8], response: &mut [u8]) -> Result<(usize, u8), RFIDError> {
+        let total = header.len() + data.len();
+        let byte = |i: usize| if i < header.len() { header[i] } else { data[i - header.len()] };
+
+        let mut code = command;
+        let mut offset = 0;
+        let mut length = 0;
+        loop {
+            let end = (offset + FRAME_PAYLOAD).min(total);
+            let mut chunk = [0u8; FRAME_PAYLOAD];
+            for (i, slot) in (offset..end).zip(chunk.iter_mut()) {
+                *slot = byte(i);
+            }
+
+            let mut answer = [0u8; MAX_FRAME_SIZE];
+            let (status, received) = self.exchange(code, &chunk[..end - offset], &mut answer)?;
+            offset = end;
+            code = DF_ADDITIONAL_FRAME;
+
+            // The PICC asks for the rest of the command
+            if offset < total {
+                if status != DF_ADDITIONAL_FRAME || received != 0 {
+                    return Err(status_error(status));
+                }
+                continue;
+            }
+
+            response
+                .get_mut(length..length + received)
+                .ok_or(RFIDError::NoRoom)?
+                .copy_from_slice(&answer[..received]);
+            length += received;
+            match status {
+                DF_OPERATION_OK | DF_NO_CHANGES => return Ok((length, status)),
+                DF_ADDITIONAL_FRAME => continue,
+                other => return Err(status_error(other)),
+            }
+        }
+    }
+
+    // Exchanges one frame: `code` with `payload`, returns the status code and the length of
+    // the answer data, which is moved to the start of `answer`
+    fn exchange(&mut self, code: u8, payload: &[u8], answer: &mut [u8; MAX_FRAME_SIZE]) -> Result<(u8, usize), RFIDError> {
+        let mut frame = [0u8; FRAME_PAYLOAD + 6];
+        let mut pos = match self.framing {
+            Framing::IsoWrapped if !payload.is_empty() => {
+                frame[..5].copy_from_slice(&[DF_ISO_CLA, code, 0x00, 0x00, payload.len() as u8]);
+                5
+            }
+            Framing::IsoWrapped => {
+                frame[..4].copy_from_slice(&[DF_ISO_CLA, code, 0x00, 0x00]);
+                4
+            }
+            Framing::Native => {
+                frame[0] = code;
+                1
+            }
+        };
+        frame.get_mut(pos..pos + payload.len()).ok_or(RFIDError::NoRoom)?.copy_from_slice(payload);
+        pos += payload.len();
+        if self.framing == Framing::IsoWrapped {
+            frame[pos] = 0x00; // Le
+            pos += 1;
+        }
+
+        let received = self.rfid.iso_dep_transceive(self.serial, &frame[..pos], answer)?;
+        let (status, start, length) = {
+            let (status, data) = self.split_status(&answer[..received])?;
+            let start = if self.framing == Framing::Native { 1 } else { 0 };
+            (status, start, data.len())
+        };
+        answer.copy_within(start..start + length, 0);
+        Ok((status, length))
+    }
+
+    // Separates the status code from the answer data
+    fn split_status<'r>(&self, answer: &'r [u8]) -> Result<(u8, &'r [u8]), RFIDError> {
+        match self.framing {
+            Framing::Native => {
+                let (&status, payload) = answer.split_first().ok_or(RFIDError::InvalidResponse)?;
+                Ok((status, payload))
+            }
+            Framing::IsoWrapped => {
+                if answer.len() < 2 {
+                    return Err(RFIDError::InvalidResponse);
+                }
+                let (payload, sw) = answer.split_at(answer.len() - 2);
+                if sw[0] != DF_ISO_SW1 {
+                    return Err(RFIDError::Status(u16::from_be_bytes([sw[0], sw[1]])));
+                }
+                Ok((sw[1], payload))
+            }
+        }
+    }
+}
+
+fn status_error(status: u8) -> RFIDError {
+    match status {
+        DF_ADDITIONAL_FRAME | DF_OPERATION_OK => RFIDError::InvalidResponse,
+        other => RFIDError::Desfire(other),
+    }
+}
+
+// File number, 3 byte offset and 3 byte length as used by ReadData, WriteData and ReadRecords
+fn offset_length(file_no: u8, offset: u32, length: u32) -> [u8; 7] {
+    let offset = offset.to_le_bytes();
+    let length = length.to_le_bytes();
+    [file_no, offset[0], offset[1], offset[2], length[0], length[1], length[2]]
+}
+
+pub(crate) fn le24(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
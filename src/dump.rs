@@ -0,0 +1,169 @@
+// src/dump.rs
+// Full MIFARE Classic card dump, the equivalent of PICC_DumpToSerial from the Arduino MFRC522 library
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::{CardType, Uid};
+use crate::errors::RFIDError;
+use crate::mifare::{self, Key, KeyType, MF_BLOCK_SIZE, MF_KEY_SIZE};
+use crate::rfid_rc522::RfidRc522;
+
+pub const MAX_SECTORS: usize = 40;
+pub const MAX_BLOCKS: usize = 256;
+
+// Key that opened a sector during a dump
+#[derive(Clone, Copy, PartialEq)]
+pub struct SectorKey {
+    pub key_type: KeyType,
+    pub key: Key,
+}
+
+// Whole card in memory, over 4 KiB for MAX_BLOCKS: more than the 2 KiB SRAM of the Uno, so
+// meant for hosts (see dump_formats). On the Uno, dump with dump_classic_with instead.
+pub struct ClassicDump {
+    pub uid: Uid,
+    pub card_type: CardType,
+    pub blocks: [[u8; MF_BLOCK_SIZE]; MAX_BLOCKS],
+    pub sector_keys: [Option<SectorKey>; MAX_SECTORS], // None if no key opened the sector
+    unreadable: [u8; MAX_BLOCKS / 8],                  // One bit per block
+}
+
+impl ClassicDump {
+    pub fn new(uid: Uid, card_type: CardType) -> Self {
+        ClassicDump {
+            uid,
+            card_type,
+            blocks: [[0u8; MF_BLOCK_SIZE]; MAX_BLOCKS],
+            sector_keys: [None; MAX_SECTORS],
+            unreadable: [0u8; MAX_BLOCKS / 8],
+        }
+    }
+
+    pub fn sector_count(&self) -> u8 {
+        mifare::sector_count(&self.card_type)
+    }
+
+    pub fn block_count(&self) -> usize {
+        match self.sector_count() {
+            0 => 0,
+            sectors => mifare::sector_trailer(sectors - 1) as usize + 1,
+        }
+    }
+
+    pub fn is_readable(&self, block: u8) -> bool {
+        self.unreadable[block as usize / 8] & (1 << (block % 8)) == 0
+    }
+
+    pub fn set_readable(&mut self, block: u8, readable: bool) {
+        if readable {
+            self.unreadable[block as usize / 8] &= !(1 << (block % 8));
+        } else {
+            self.unreadable[block as usize / 8] |= 1 << (block % 8);
+        }
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Reads every sector of the selected MIFARE Classic card into a ClassicDump (host only,
+    // see ClassicDump). Each key is tried as key A and then as key B until one opens the sector.
+    pub fn dump_classic<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        keys: &[Key],
+    ) -> Result<ClassicDump, RFIDError> {
+        let mut dump = ClassicDump::new(*uid, CardType::from_sak(uid.sak));
+        self.dump_classic_with(serial, uid, keys, |_, block, data, key| {
+            let sector = mifare::block_sector(block) as usize;
+            dump.sector_keys[sector] = key.copied();
+            match data {
+                Some(data) => dump.blocks[block as usize] = *data,
+                None => dump.set_readable(block, false),
+            }
+        })?;
+        Ok(dump)
+    }
+
+    // Same as dump_classic but hands every block to `on_block` instead of storing it, which
+    // keeps memory use low enough for small targets. `on_block` receives the block address,
+    // its data (None if unreadable) and the key that opened the sector (None if none did).
+    pub fn dump_classic_with<W, F>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        keys: &[Key],
+        mut on_block: F,
+    ) -> Result<(), RFIDError>
+    where
+        W: ufmt::uWrite,
+        F: FnMut(&mut W, u8, Option<&[u8; MF_BLOCK_SIZE]>, Option<&SectorKey>),
+    {
+        let sectors = mifare::sector_count(&CardType::from_sak(uid.sak));
+        if sectors == 0 {
+            return Err(RFIDError::InvalidResponse); // Not a MIFARE Classic card
+        }
+
+        for sector in 0..sectors {
+            let first_block = mifare::sector_first_block(sector);
+            let trailer = mifare::sector_trailer(sector);
+
+            let sector_key = match self.open_sector(serial, uid, trailer, keys)? {
+                Some(sector_key) => sector_key,
+                None => {
+                    for block in first_block..=trailer {
+                        on_block(serial, block, None, None);
+                    }
+                    continue;
+                }
+            };
+
+            for block in first_block..=trailer {
+                match self.mifare_read(serial, block) {
+                    Ok(mut data) => {
+                        // The key used for authentication always reads back as zeros
+                        if block == trailer {
+                            match sector_key.key_type {
+                                KeyType::A => data[..MF_KEY_SIZE].copy_from_slice(&sector_key.key.0),
+                                KeyType::B => data[MF_BLOCK_SIZE - MF_KEY_SIZE..].copy_from_slice(&sector_key.key.0),
+                            }
+                        }
+                        on_block(serial, block, Some(&data), Some(&sector_key));
+                    }
+                    Err(_) => {
+                        on_block(serial, block, None, Some(&sector_key));
+                        // A refused read drops the PICC out of the authenticated state
+                        self.reselect(serial, uid)?;
+                        self.mifare_authenticate(serial, sector_key.key_type, trailer, &sector_key.key, uid)?;
+                    }
+                }
+            }
+        }
+
+        self.stop_crypto1(serial);
+        Ok(())
+    }
+
+    // Tries every key as key A and key B on a sector trailer
+    fn open_sector<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        trailer: u8,
+        keys: &[Key],
+    ) -> Result<Option<SectorKey>, RFIDError> {
+        for key in keys {
+            for key_type in [KeyType::A, KeyType::B] {
+                if self.mifare_authenticate(serial, key_type, trailer, key, uid).is_ok() {
+                    return Ok(Some(SectorKey { key_type, key: *key }));
+                }
+                // A failed authentication sends the PICC back to IDLE or HALT
+                self.reselect(serial, uid)?;
+            }
+        }
+        Ok(None)
+    }
+}
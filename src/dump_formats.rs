@@ -0,0 +1,355 @@
+// src/dump_formats.rs
+// Import/export of ClassicDump in the file formats used by desktop tools:
+// raw binary (.mfd/.bin), Proxmark3 JSON, Flipper Zero .nfc and hex text (one block per line, .eml)
+
+use std::fmt::{self, Write};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use crate::card_types::{CardType, Uid};
+use crate::dump::{ClassicDump, SectorKey};
+use crate::mifare::{self, Key, KeyType, MF_BLOCK_SIZE, MF_KEY_SIZE};
+
+#[derive(Debug, PartialEq)]
+pub enum DumpFormatError {
+    InvalidLength(usize), // Size or block count doesn't match a MIFARE Classic card
+    InvalidHex,
+    InvalidJson,
+    MissingField(&'static str),
+    UnsupportedCardType,
+}
+
+impl fmt::Display for DumpFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpFormatError::InvalidLength(len) => write!(f, "invalid dump length: {}", len),
+            DumpFormatError::InvalidHex => write!(f, "invalid hex data"),
+            DumpFormatError::InvalidJson => write!(f, "invalid JSON"),
+            DumpFormatError::MissingField(field) => write!(f, "missing field: {}", field),
+            DumpFormatError::UnsupportedCardType => write!(f, "unsupported card type"),
+        }
+    }
+}
+
+impl std::error::Error for DumpFormatError {}
+
+// Raw binary dump, unreadable blocks are written as zeros
+pub fn to_mfd(dump: &ClassicDump) -> Vec<u8> {
+    dump.blocks[..dump.block_count()].concat()
+}
+
+pub fn from_mfd(data: &[u8]) -> Result<ClassicDump, DumpFormatError> {
+    let card_type = card_type_for_blocks(data.len() / MF_BLOCK_SIZE)
+        .filter(|_| data.len() % MF_BLOCK_SIZE == 0)
+        .ok_or(DumpFormatError::InvalidLength(data.len()))?;
+
+    let mut blocks = [[0u8; MF_BLOCK_SIZE]; crate::dump::MAX_BLOCKS];
+    for (block, chunk) in blocks.iter_mut().zip(data.chunks(MF_BLOCK_SIZE)) {
+        block.copy_from_slice(chunk);
+    }
+    Ok(build_dump(None, card_type, &blocks, &[]))
+}
+
+// Proxmark3 `hf mf dump` JSON, unreadable blocks are written as zeros
+pub fn to_proxmark_json(dump: &ClassicDump) -> String {
+    let atqa = atqa_for(&dump.uid, &dump.card_type);
+
+    let mut blocks = serde_json::Map::new();
+    for block in 0..dump.block_count() {
+        blocks.insert(block.to_string(), hex(&dump.blocks[block], "").into());
+    }
+
+    let mut sector_keys = serde_json::Map::new();
+    for sector in 0..dump.sector_count() {
+        let trailer = &dump.blocks[mifare::sector_trailer(sector) as usize];
+        sector_keys.insert(
+            sector.to_string(),
+            serde_json::json!({
+                "KeyA": hex(&trailer[..MF_KEY_SIZE], ""),
+                "KeyB": hex(&trailer[MF_BLOCK_SIZE - MF_KEY_SIZE..], ""),
+            }),
+        );
+    }
+
+    let json = serde_json::json!({
+        "Created": "rfid_rc522",
+        "FileType": "mfcard",
+        "Card": {
+            "UID": hex(dump.uid.as_bytes(), ""),
+            "ATQA": hex(&atqa, ""),
+            "SAK": hex(&[dump.uid.sak], ""),
+        },
+        "blocks": blocks,
+        "SectorKeys": sector_keys,
+    });
+    serde_json::to_string_pretty(&json).unwrap_or_default()
+}
+
+pub fn from_proxmark_json(text: &str) -> Result<ClassicDump, DumpFormatError> {
+    let json: serde_json::Value = serde_json::from_str(text).map_err(|_| DumpFormatError::InvalidJson)?;
+
+    let blocks_json = json
+        .get("blocks")
+        .and_then(|blocks| blocks.as_object())
+        .ok_or(DumpFormatError::MissingField("blocks"))?;
+    let card_type = card_type_for_blocks(blocks_json.len()).ok_or(DumpFormatError::InvalidLength(blocks_json.len()))?;
+
+    let mut blocks = [[0u8; MF_BLOCK_SIZE]; crate::dump::MAX_BLOCKS];
+    let mut unknown = Vec::new();
+    for (index, data) in blocks_json {
+        let block: usize = index.parse().map_err(|_| DumpFormatError::InvalidJson)?;
+        if block >= blocks_json.len() {
+            return Err(DumpFormatError::InvalidLength(block));
+        }
+        let data = data.as_str().ok_or(DumpFormatError::InvalidJson)?;
+        if !parse_hex(data, &mut blocks[block])? {
+            unknown.push(block as u8);
+        }
+    }
+
+    let uid = match json.pointer("/Card/UID").and_then(|uid| uid.as_str()) {
+        Some(uid) => Some(parse_uid(uid, &card_type)?),
+        None => None,
+    };
+    Ok(build_dump(uid, card_type, &blocks, &unknown))
+}
+
+// Flipper Zero NFC device file, unreadable bytes are written as ??
+pub fn to_flipper_nfc(dump: &ClassicDump) -> String {
+    let atqa = atqa_for(&dump.uid, &dump.card_type);
+    let type_name = match dump.card_type {
+        CardType::MifareMini => "MINI",
+        CardType::Mifare4K => "4K",
+        _ => "1K",
+    };
+
+    let mut out = String::new();
+    out.push_str("Filetype: Flipper NFC device\n");
+    out.push_str("Version: 4\n");
+    out.push_str("# Device type can be ISO14443-3A, ISO14443-3B, ISO14443-4A, NTAG/Ultralight, Mifare Classic, Mifare DESFire, SLIX, ST25TB\n");
+    out.push_str("Device type: Mifare Classic\n");
+    out.push_str("# UID is common for all formats\n");
+    let _ = writeln!(out, "UID: {}", hex(dump.uid.as_bytes(), " "));
+    out.push_str("# ISO14443-3A specific data\n");
+    let _ = writeln!(out, "ATQA: {:02X} {:02X}", atqa[1], atqa[0]);
+    let _ = writeln!(out, "SAK: {:02X}", dump.uid.sak);
+    out.push_str("# Mifare Classic specific data\n");
+    let _ = writeln!(out, "Mifare Classic type: {}", type_name);
+    out.push_str("Data format version: 2\n");
+    out.push_str("# Mifare Classic blocks, '??' means unknown data\n");
+    for block in 0..dump.block_count() {
+        let _ = writeln!(out, "Block {}: {}", block, block_hex(dump, block, " "));
+    }
+    out
+}
+
+pub fn from_flipper_nfc(text: &str) -> Result<ClassicDump, DumpFormatError> {
+    let mut uid_text = None;
+    let mut card_type = None;
+    let mut blocks = [[0u8; MF_BLOCK_SIZE]; crate::dump::MAX_BLOCKS];
+    let mut seen = [false; crate::dump::MAX_BLOCKS];
+    let mut unknown = Vec::new();
+
+    for line in text.lines().map(str::trim).filter(|line| !line.starts_with('#')) {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "Device type" if value != "Mifare Classic" => return Err(DumpFormatError::UnsupportedCardType),
+            "UID" => uid_text = Some(value),
+            "Mifare Classic type" => {
+                card_type = Some(match value {
+                    "MINI" => CardType::MifareMini,
+                    "1K" => CardType::Mifare1K,
+                    "4K" => CardType::Mifare4K,
+                    _ => return Err(DumpFormatError::UnsupportedCardType),
+                })
+            }
+            _ => {
+                if let Some(index) = key.strip_prefix("Block ") {
+                    let block: usize = index.trim().parse().map_err(|_| DumpFormatError::InvalidHex)?;
+                    if block >= blocks.len() {
+                        return Err(DumpFormatError::InvalidLength(block));
+                    }
+                    if !parse_hex(value, &mut blocks[block])? {
+                        unknown.push(block as u8);
+                    }
+                    seen[block] = true;
+                }
+            }
+        }
+    }
+
+    let card_type = card_type.ok_or(DumpFormatError::MissingField("Mifare Classic type"))?;
+    let block_count = mifare::sector_trailer(mifare::sector_count(&card_type) - 1) as usize + 1;
+    // Blocks past the declared card size
+    if let Some(block) = (block_count..seen.len()).find(|&block| seen[block]) {
+        return Err(DumpFormatError::InvalidLength(block));
+    }
+    // Blocks missing from the file are unknown as well
+    unknown.extend((0..block_count).filter(|&block| !seen[block]).map(|block| block as u8));
+
+    let uid = match uid_text {
+        Some(uid) => Some(parse_uid(uid, &card_type)?),
+        None => None,
+    };
+    Ok(build_dump(uid, card_type, &blocks, &unknown))
+}
+
+// One block per line as 32 hex digits, unreadable bytes are written as ??
+pub fn to_hex_text(dump: &ClassicDump) -> String {
+    let mut out = String::new();
+    for block in 0..dump.block_count() {
+        out.push_str(&block_hex(dump, block, ""));
+        out.push('\n');
+    }
+    out
+}
+
+pub fn from_hex_text(text: &str) -> Result<ClassicDump, DumpFormatError> {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let card_type = card_type_for_blocks(lines.len()).ok_or(DumpFormatError::InvalidLength(lines.len()))?;
+
+    let mut blocks = [[0u8; MF_BLOCK_SIZE]; crate::dump::MAX_BLOCKS];
+    let mut unknown = Vec::new();
+    for (block, line) in lines.iter().enumerate() {
+        if !parse_hex(line, &mut blocks[block])? {
+            unknown.push(block as u8);
+        }
+    }
+    Ok(build_dump(None, card_type, &blocks, &unknown))
+}
+
+fn card_type_for_blocks(blocks: usize) -> Option<CardType> {
+    match blocks {
+        20 => Some(CardType::MifareMini),
+        64 => Some(CardType::Mifare1K),
+        256 => Some(CardType::Mifare4K),
+        _ => None,
+    }
+}
+
+fn sak_for(card_type: &CardType) -> u8 {
+    match card_type {
+        CardType::MifareMini => 0x09,
+        CardType::Mifare4K => 0x18,
+        _ => 0x08,
+    }
+}
+
+// ATQA as sent over the air (LSB first), derived from the UID size and card type
+fn atqa_for(uid: &Uid, card_type: &CardType) -> [u8; 2] {
+    let uid_size_bits = match uid.size {
+        7 => 0x40,
+        10 => 0x80,
+        _ => 0x00,
+    };
+    let capacity = match card_type {
+        CardType::Mifare4K => 0x02,
+        _ => 0x04,
+    };
+    [uid_size_bits | capacity, 0x00]
+}
+
+fn parse_uid(text: &str, card_type: &CardType) -> Result<Uid, DumpFormatError> {
+    let mut uid = Uid::new();
+    let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let size = digits.len() / 2;
+    if digits.len() % 2 != 0 || !matches!(size, 4 | 7 | 10) {
+        return Err(DumpFormatError::InvalidHex);
+    }
+    if !parse_hex(&digits, &mut uid.bytes[..size])? {
+        return Err(DumpFormatError::InvalidHex);
+    }
+    uid.size = size as u8;
+    uid.sak = sak_for(card_type);
+    Ok(uid)
+}
+
+// Single size UIDs are followed by their BCC in block 0, double size UIDs are not
+fn uid_from_block0(block0: &[u8; MF_BLOCK_SIZE], card_type: &CardType) -> Uid {
+    let mut uid = Uid::new();
+    let bcc = block0[0] ^ block0[1] ^ block0[2] ^ block0[3];
+    uid.size = if block0[4] == bcc { 4 } else { 7 };
+    uid.bytes[..uid.size as usize].copy_from_slice(&block0[..uid.size as usize]);
+    uid.sak = sak_for(card_type);
+    uid
+}
+
+fn build_dump(
+    uid: Option<Uid>,
+    card_type: CardType,
+    blocks: &[[u8; MF_BLOCK_SIZE]; crate::dump::MAX_BLOCKS],
+    unknown: &[u8],
+) -> ClassicDump {
+    let uid = uid.unwrap_or_else(|| uid_from_block0(&blocks[0], &card_type));
+    let mut dump = ClassicDump::new(uid, card_type);
+    dump.blocks = *blocks;
+    for &block in unknown {
+        dump.set_readable(block, false);
+    }
+
+    // Dumps store the known keys in the sector trailers
+    for sector in 0..dump.sector_count() {
+        let trailer = mifare::sector_trailer(sector);
+        if dump.is_readable(trailer) {
+            let mut key = Key([0u8; MF_KEY_SIZE]);
+            key.0.copy_from_slice(&dump.blocks[trailer as usize][..MF_KEY_SIZE]);
+            dump.sector_keys[sector as usize] = Some(SectorKey { key_type: KeyType::A, key });
+        }
+    }
+    dump
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    let mut out = String::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            out.push_str(separator);
+        }
+        let _ = write!(out, "{:02X}", byte);
+    }
+    out
+}
+
+fn block_hex(dump: &ClassicDump, block: usize, separator: &str) -> String {
+    if dump.is_readable(block as u8) {
+        hex(&dump.blocks[block], separator)
+    } else {
+        let mut out = String::new();
+        for i in 0..MF_BLOCK_SIZE {
+            if i > 0 {
+                out.push_str(separator);
+            }
+            out.push_str("??");
+        }
+        out
+    }
+}
+
+// Parses hex digits (optionally space separated) into `out`.
+// Returns false if any byte is unknown (?? or --), which is left as zero.
+fn parse_hex(text: &str, out: &mut [u8]) -> Result<bool, DumpFormatError> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() != out.len() * 2 {
+        return Err(DumpFormatError::InvalidHex);
+    }
+
+    let mut known = true;
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        match (pair[0].to_digit(16), pair[1].to_digit(16)) {
+            (Some(high), Some(low)) => *byte = (high << 4 | low) as u8,
+            _ if matches!(pair, ['?', '?'] | ['-', '-']) => {
+                *byte = 0;
+                known = false;
+            }
+            _ => return Err(DumpFormatError::InvalidHex),
+        }
+    }
+    Ok(known)
+}
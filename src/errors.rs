@@ -1,5 +1,7 @@
 use core::fmt::{Debug, Formatter, Result};
 use ufmt::{uDebug, uWrite};
+use crate::apdu::StatusWord;
+use crate::desfire::DesfireStatus;
 
 #[derive(PartialEq)]
 pub enum RFIDError {
@@ -10,6 +12,15 @@ pub enum RFIDError {
     CrcError,      // New CrcError variant
     NoRoom,
     Collision,
+    AuthenticationFailed,
+    Nak(u8), // 4 bit NAK code returned by the PICC, see NakReason
+    InvalidBlock0,
+    NotNdefFormatted, // No valid Capability Container or NDEF TLV/file
+    InvalidNdef,      // Malformed TLV or NDEF data
+    ReadOnly,
+    Status(u16), // SW1 SW2 of a failed APDU, see StatusWord
+    Desfire(u8), // DESFire status code, see DesfireStatus
+    IntegrityError, // MAC or CRC of a secure messaging answer did not match
 }
 
 impl Debug for RFIDError {
@@ -22,7 +33,15 @@ impl Debug for RFIDError {
             RFIDError::CrcError => write!(f, "CrcError"),
             RFIDError::NoRoom => write!(f, "No room or we"),
             RFIDError::Collision => write!(f, "Collision i guess"),
-            
+            RFIDError::AuthenticationFailed => write!(f, "AuthenticationFailed"),
+            RFIDError::Nak(code) => write!(f, "Nak(0x{:X})", code),
+            RFIDError::InvalidBlock0 => write!(f, "InvalidBlock0"),
+            RFIDError::NotNdefFormatted => write!(f, "NotNdefFormatted"),
+            RFIDError::InvalidNdef => write!(f, "InvalidNdef"),
+            RFIDError::ReadOnly => write!(f, "ReadOnly"),
+            RFIDError::Status(sw) => write!(f, "Status(0x{:04X})", sw),
+            RFIDError::Desfire(code) => write!(f, "Desfire(0x{:02X})", code),
+            RFIDError::IntegrityError => write!(f, "IntegrityError"),
         }
     }
 }
@@ -41,6 +60,63 @@ impl uDebug for RFIDError {
             RFIDError::CrcError => f.write_str("CrcError"),
             RFIDError::NoRoom => f.write_str("No room or we"),
             RFIDError::Collision => f.write_str("Collision i guess"),
+            RFIDError::AuthenticationFailed => f.write_str("AuthenticationFailed"),
+            RFIDError::Nak(code) => ufmt::uwrite!(f, "Nak({})", code),
+            RFIDError::InvalidBlock0 => f.write_str("InvalidBlock0"),
+            RFIDError::NotNdefFormatted => f.write_str("NotNdefFormatted"),
+            RFIDError::InvalidNdef => f.write_str("InvalidNdef"),
+            RFIDError::ReadOnly => f.write_str("ReadOnly"),
+            RFIDError::Status(sw) => ufmt::uwrite!(f, "Status(0x{:X})", sw),
+            RFIDError::Desfire(code) => ufmt::uwrite!(f, "Desfire(0x{:X})", code),
+            RFIDError::IntegrityError => f.write_str("IntegrityError"),
+        }
+    }
+}
+
+// Meaning of a NAK code as defined for MIFARE Ultralight and NTAG
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NakReason {
+    InvalidArgument,     // 0x0 - e.g. invalid page address
+    ParityOrCrcError,    // 0x1
+    AuthCounterOverflow, // 0x4 - invalid authentication counter overflow
+    EepromWriteError,    // 0x5
+    Unknown(u8),
+}
+
+impl NakReason {
+    pub fn from_code(code: u8) -> NakReason {
+        match code & 0x0F {
+            0x0 => NakReason::InvalidArgument,
+            0x1 => NakReason::ParityOrCrcError,
+            0x4 => NakReason::AuthCounterOverflow,
+            0x5 => NakReason::EepromWriteError,
+            other => NakReason::Unknown(other),
+        }
+    }
+}
+
+impl RFIDError {
+    // Decoded reason if the PICC answered with a NAK
+    pub fn nak_reason(&self) -> Option<NakReason> {
+        match self {
+            RFIDError::Nak(code) => Some(NakReason::from_code(*code)),
+            _ => None,
+        }
+    }
+
+    // Decoded status word if an APDU failed
+    pub fn status_word(&self) -> Option<StatusWord> {
+        match self {
+            RFIDError::Status(sw) => Some(StatusWord::from_sw((*sw >> 8) as u8, *sw as u8)),
+            _ => None,
+        }
+    }
+
+    // Decoded status if a DESFire command failed
+    pub fn desfire_status(&self) -> Option<DesfireStatus> {
+        match self {
+            RFIDError::Desfire(code) => Some(DesfireStatus::from_code(*code)),
+            _ => None,
         }
     }
 }
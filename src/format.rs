@@ -0,0 +1,82 @@
+// src/format.rs
+// Prepares blank tags for NDEF: a Capability Container and an empty NDEF TLV on Type 2 Tags,
+// MAD and NDEF sector trailers on MIFARE Classic. Every format is read back afterwards.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::{CardType, Uid};
+use crate::errors::RFIDError;
+use crate::mifare::Key;
+use crate::rfid_rc522::RfidRc522;
+use crate::type2::{self, CapabilityContainer, CC_PAGE, DATA_AREA_START, TLV_NDEF, TLV_TERMINATOR};
+use crate::ultralight::UL_PAGE_SIZE;
+
+// Keys used when formatting MIFARE Classic cards, ignored for Type 2 Tags
+#[derive(Clone, Copy, PartialEq)]
+pub struct FormatOptions {
+    pub classic_key: Key,   // Current key A of every sector
+    pub classic_key_b: Key, // New key B of every sector, needed to change the MAD or trailers later
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { classic_key: Key::DEFAULT, classic_key_b: Key::DEFAULT }
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Formats the selected card for NDEF according to its type (see detect_card_type) and
+    // checks that it reads back as an empty NDEF message
+    pub fn format_ndef<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        card_type: &CardType,
+        options: &FormatOptions,
+    ) -> Result<(), RFIDError> {
+        let mut buffer = [0u8; 1];
+        let length = match card_type {
+            CardType::MifareMini | CardType::Mifare1K | CardType::Mifare4K => {
+                self.classic_format_ndef(serial, uid, card_type, &options.classic_key, &options.classic_key_b)?;
+                // The PICC is still authenticated after stop_crypto1: HLTA, sent in plain, drops
+                // it out of that state, then WUPA and select start a fresh session
+                self.halt_a(serial).ok();
+                self.reselect(serial, uid)?;
+                self.classic_read_ndef(serial, uid, &mut buffer)?
+            }
+            _ if card_type.is_type2() => {
+                self.type2_format_ndef(serial, card_type)?;
+                self.type2_read_ndef(serial, &mut buffer)?
+            }
+            _ => return Err(RFIDError::InvalidResponse),
+        };
+        if length != 0 {
+            return Err(RFIDError::InvalidNdef);
+        }
+        Ok(())
+    }
+
+    // Writes the CC and an empty NDEF TLV. The CC page is one-time programmable: a tag that
+    // already carries the same CC is left as is, one with a different CC is rejected.
+    fn type2_format_ndef<W: ufmt::uWrite>(&mut self, serial: &mut W, card_type: &CardType) -> Result<(), RFIDError> {
+        let size = type2::data_area_size(card_type).ok_or(RFIDError::InvalidResponse)?;
+        let cc = CapabilityContainer::new(size).to_bytes();
+
+        let data = self.ultralight_read(serial, CC_PAGE)?;
+        let current = [data[0], data[1], data[2], data[3]];
+        if current != cc {
+            // OTP bits can only be set, the result would be neither CC
+            if current.iter().zip(cc.iter()).any(|(have, want)| have & !want != 0) {
+                return Err(RFIDError::ReadOnly);
+            }
+            self.ultralight_write(serial, CC_PAGE, &cc)?;
+        }
+
+        let first_page = (DATA_AREA_START / UL_PAGE_SIZE as u16) as u8;
+        self.ultralight_write(serial, first_page, &[TLV_NDEF, 0x00, TLV_TERMINATOR, 0x00])
+    }
+}
@@ -0,0 +1,60 @@
+// src/inventory.rs
+// Reading every PICC in the field: REQA wakes the PICCs in IDLE state, anticollision selects
+// one of them and HLTA puts it to sleep, so the next REQA is only answered by the others.
+// Repeats until no PICC answers. The PICCs are left halted, WUPA wakes them up again.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use heapless::Vec;
+use crate::card_types::Uid;
+use crate::errors::RFIDError;
+use crate::registers::REQA;
+use crate::rfid_rc522::RfidRc522;
+
+// Failed rounds in a row after which the remaining PICCs are given up
+const MAX_FAILED_ROUNDS: u8 = 3;
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Collects the UIDs of up to `max` (and at most N) PICCs in the field. PICCs that do not
+    // complete anticollision within a few rounds, e.g. at the edge of the field, are skipped.
+    pub fn inventory<W: ufmt::uWrite, const N: usize>(
+        &mut self,
+        serial: &mut W,
+        max: usize,
+    ) -> Result<Vec<Uid, N>, RFIDError> {
+        let mut uids: Vec<Uid, N> = Vec::new();
+        let mut failed_rounds = 0;
+        while uids.len() < max.min(N) && failed_rounds < MAX_FAILED_ROUNDS {
+            // Differing ATQAs collide, which still means PICCs are there
+            let mut atqa = [0u8; 2];
+            let mut atqa_size = 2;
+            match self.picc_reqa_or_wupa(serial, REQA, &mut atqa, &mut atqa_size) {
+                Ok(()) | Err(RFIDError::Collision) => {}
+                Err(RFIDError::Timeout) => break,
+                Err(_) => {
+                    failed_rounds += 1;
+                    continue;
+                }
+            }
+
+            let mut uid = Uid::new();
+            if self.picc_select(serial, &mut uid, 0).is_err() {
+                failed_rounds += 1;
+                continue;
+            }
+            if !uids.contains(&uid) {
+                uids.push(uid).ok();
+                failed_rounds = 0;
+            } else {
+                failed_rounds += 1;
+            }
+            // A PICC that did not halt is caught by the check above
+            self.halt_a(serial).ok();
+        }
+        Ok(uids)
+    }
+}
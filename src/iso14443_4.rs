@@ -0,0 +1,533 @@
+// src/iso14443_4.rs
+// ISO/IEC 14443-4 activation of PICCs that announce it in their SAK (bit 0x20): RATS and the
+// Answer To Select. The ATS sets the largest frame the PICC accepts (FSC), how long it may take
+// to answer (FWT) and whether it understands CID and NAD. Every later ISO-DEP frame uses them.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::errors::RFIDError;
+use crate::registers::{MODE_WIDTH_REG, PICC_CMD_PPS, PICC_CMD_RATS, RX_MODE_REG, TX_MODE_REG};
+use crate::rfid_rc522::RfidRc522;
+
+// The MFRC522 FIFO holds one whole frame, CRC included
+pub const MAX_FRAME_SIZE: usize = 64;
+
+// Largest FSDI whose frame size fits the FIFO (FSDI 5, FSD = 64)
+pub const FSDI_MAX: u8 = 5;
+pub const CID_MAX: u8 = 14;
+
+// Defaults for a missing T0 or TB byte
+const FSCI_DEFAULT: u8 = 2;
+const FWI_DEFAULT: u8 = 4;
+const SFGI_DEFAULT: u8 = 0;
+
+// T0 format byte: FSCI in the low nibble, then TA/TB/TC presence
+const T0_TA: u8 = 0x10;
+const T0_TB: u8 = 0x20;
+const T0_TC: u8 = 0x40;
+
+// TC: protocol options
+const TC_NAD: u8 = 0x01;
+const TC_CID: u8 = 0x02;
+
+// TA: divisors supported from PCD to PICC (DR) and from PICC to PCD (DS), and whether both
+// directions must use the same one. Bit 3 set is reserved for future use.
+const TA_SAME_D: u8 = 0x80;
+const TA_RFU: u8 = 0x08;
+
+// PPS0: PPS1 follows
+const PPS0_PPS1: u8 = 0x11;
+
+// Protocol Control Byte of the three block types
+const PCB_I_BLOCK: u8 = 0x02;
+const PCB_R_ACK: u8 = 0xA2;
+const PCB_R_NAK: u8 = 0xB2;
+const PCB_S_DESELECT: u8 = 0xC2;
+const PCB_S_WTX: u8 = 0xF2;
+const PCB_CHAINING: u8 = 0x10;
+const PCB_CID: u8 = 0x08;
+const PCB_NAD: u8 = 0x04;
+const PCB_BLOCK_NUMBER: u8 = 0x01;
+
+// Transmission errors tolerated on one block before giving up
+const MAX_RETRIES: u8 = 2;
+
+// Frame sizes for FSCI/FSDI 0-8, larger values are reserved and read as 256
+const FRAME_SIZES: [u16; 9] = [16, 24, 32, 40, 48, 64, 96, 128, 256];
+
+pub fn frame_size(index: u8) -> u16 {
+    FRAME_SIZES[(index as usize).min(FRAME_SIZES.len() - 1)]
+}
+
+// (256 * 16 / fc) * 2^FWI, rounded up to 100us steps. FWI 15 is reserved and means the default.
+pub fn fwt_us(fwi: u8) -> u32 {
+    let fwi = if fwi > 14 { FWI_DEFAULT } else { fwi };
+    (4096u32 << fwi).div_ceil(1356) * 100
+}
+
+// Same unit as the FWT, 0 for SFGI 0 (no guard time)
+pub fn sfgt_us(sfgi: u8) -> u32 {
+    match sfgi {
+        0 | 15 => 0,
+        _ => fwt_us(sfgi),
+    }
+}
+
+// ISO/IEC 14443 bit rates, fc/128 times the divisor D
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum BitRate {
+    Kbps106,
+    Kbps212,
+    Kbps424,
+    Kbps848,
+}
+
+impl BitRate {
+    const ALL: [BitRate; 4] = [BitRate::Kbps106, BitRate::Kbps212, BitRate::Kbps424, BitRate::Kbps848];
+
+    // DRI/DSI of PPS1, also the TxSpeed/RxSpeed value of the MFRC522
+    pub fn index(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn kbps(&self) -> u16 {
+        106 << self.index()
+    }
+
+    // ModWidthReg value recommended for the pause length of this bit rate
+    fn mod_width(&self) -> u8 {
+        match self {
+            BitRate::Kbps106 => 0x26,
+            BitRate::Kbps212 => 0x15,
+            BitRate::Kbps424 => 0x0A,
+            BitRate::Kbps848 => 0x05,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Ats {
+    pub fsci: u8,
+    pub ta: Option<u8>, // Supported bit rates
+    pub tb: Option<u8>, // FWI and SFGI
+    pub tc: Option<u8>, // NAD and CID support
+    pub fwi: u8,
+    pub sfgi: u8,
+    pub cid_supported: bool,
+    pub nad_supported: bool,
+    historical: [u8; MAX_FRAME_SIZE],
+    historical_len: usize,
+}
+
+impl Ats {
+    // Parses an ATS without its CRC, starting with the length byte TL
+    pub fn parse(data: &[u8]) -> Result<Self, RFIDError> {
+        let length = *data.first().ok_or(RFIDError::InvalidResponse)? as usize;
+        if length == 0 || length != data.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+
+        let mut ats = Ats {
+            fsci: FSCI_DEFAULT,
+            ta: None,
+            tb: None,
+            tc: None,
+            fwi: FWI_DEFAULT,
+            sfgi: SFGI_DEFAULT,
+            cid_supported: true, // Both default to "CID supported, NAD not"
+            nad_supported: false,
+            historical: [0u8; MAX_FRAME_SIZE],
+            historical_len: 0,
+        };
+        if length == 1 {
+            return Ok(ats);
+        }
+
+        let t0 = data[1];
+        ats.fsci = t0 & 0x0F;
+        let mut pos = 2;
+        for (flag, byte) in [(T0_TA, &mut ats.ta), (T0_TB, &mut ats.tb), (T0_TC, &mut ats.tc)] {
+            if t0 & flag != 0 {
+                *byte = Some(*data.get(pos).ok_or(RFIDError::InvalidResponse)?);
+                pos += 1;
+            }
+        }
+        if let Some(tb) = ats.tb {
+            ats.fwi = tb >> 4;
+            ats.sfgi = tb & 0x0F;
+        }
+        if let Some(tc) = ats.tc {
+            ats.cid_supported = tc & TC_CID != 0;
+            ats.nad_supported = tc & TC_NAD != 0;
+        }
+
+        let historical = &data[pos..];
+        if historical.len() > MAX_FRAME_SIZE {
+            return Err(RFIDError::NoRoom);
+        }
+        ats.historical[..historical.len()].copy_from_slice(historical);
+        ats.historical_len = historical.len();
+        Ok(ats)
+    }
+
+    pub fn historical_bytes(&self) -> &[u8] {
+        &self.historical[..self.historical_len]
+    }
+
+    // Largest frame the PICC accepts, PCB to CRC
+    pub fn fsc(&self) -> u16 {
+        frame_size(self.fsci)
+    }
+
+    pub fn fwt_us(&self) -> u32 {
+        fwt_us(self.fwi)
+    }
+
+    // Guard time the PICC needs after sending the ATS
+    pub fn sfgt_us(&self) -> u32 {
+        sfgt_us(self.sfgi)
+    }
+
+    // Fastest bit rates the PICC offers up to `max`, as (PCD to PICC, PICC to PCD).
+    // Without TA only 106 kbit/s is available.
+    pub fn best_bit_rates(&self, max: BitRate) -> (BitRate, BitRate) {
+        let ta = match self.ta {
+            Some(ta) if ta & TA_RFU == 0 => ta,
+            _ => 0,
+        };
+        // DR bits start at bit 0, DS bits at bit 4
+        let supported = |rate: BitRate, shift: u8| {
+            rate == BitRate::Kbps106 || ta & (1 << (shift + rate.index() - 1)) != 0
+        };
+        let best = |usable: &dyn Fn(BitRate) -> bool| {
+            BitRate::ALL.into_iter().rev().find(|&rate| rate <= max && usable(rate)).unwrap_or(BitRate::Kbps106)
+        };
+
+        if ta & TA_SAME_D != 0 {
+            let rate = best(&|rate| supported(rate, 0) && supported(rate, 4));
+            return (rate, rate);
+        }
+        (best(&|rate| supported(rate, 0)), best(&|rate| supported(rate, 4)))
+    }
+}
+
+// Parameters of the activated PICC
+#[derive(Clone, Copy, PartialEq)]
+pub struct IsoDepParams {
+    pub fsc: usize, // FSC limited to the FIFO
+    pub fwt_us: u32,
+    pub cid: Option<u8>, // None if the PICC does not support CID
+    pub nad_supported: bool,
+    pub(crate) block_number: u8,
+    pub(crate) rats_cid: u8, // CID sent in RATS, PPS uses it even if the PICC ignores CIDs
+}
+
+impl IsoDepParams {
+    // PCB plus the CID byte if used
+    fn prologue(&self, pcb: u8) -> ([u8; 2], usize) {
+        match self.cid {
+            Some(cid) => ([pcb | PCB_CID, cid], 2),
+            None => ([pcb, 0], 1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    I { chaining: bool, block_number: u8 },
+    RAck { block_number: u8 },
+    WaitingTimeExtension(u8),
+    Deselect,
+}
+
+impl Block {
+    // Identifies a received block, returns it with the offset of its INF field
+    fn parse(frame: &[u8]) -> Option<(Block, usize)> {
+        let pcb = *frame.first()?;
+        let mut inf = 1 + (pcb & PCB_CID != 0) as usize;
+        let block = match pcb & !(PCB_CID | PCB_NAD | PCB_CHAINING | PCB_BLOCK_NUMBER) {
+            PCB_I_BLOCK => {
+                inf += (pcb & PCB_NAD != 0) as usize;
+                Block::I { chaining: pcb & PCB_CHAINING != 0, block_number: pcb & PCB_BLOCK_NUMBER }
+            }
+            // PICCs never send R(NAK)
+            PCB_R_ACK if pcb & (PCB_CHAINING | PCB_NAD) == 0 => Block::RAck { block_number: pcb & PCB_BLOCK_NUMBER },
+            _ if pcb & !PCB_CID == PCB_S_DESELECT => Block::Deselect,
+            _ if pcb & !PCB_CID == PCB_S_WTX => Block::WaitingTimeExtension(*frame.get(inf)? & 0x3F),
+            _ => return None,
+        };
+        if inf > frame.len() {
+            return None;
+        }
+        Some((block, inf))
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Activates the selected PICC. `fsdi` announces the largest frame we accept (at most
+    // FSDI_MAX for the FIFO) and `cid` the card identifier it gets (0-14).
+    pub fn rats<W: ufmt::uWrite>(&mut self, serial: &mut W, fsdi: u8, cid: u8) -> Result<Ats, RFIDError> {
+        if fsdi > FSDI_MAX || cid > CID_MAX {
+            return Err(RFIDError::InvalidResponse);
+        }
+        self.iso_dep = None;
+        self.set_bit_rates(serial, BitRate::Kbps106, BitRate::Kbps106);
+
+        let mut frame = [PICC_CMD_RATS, (fsdi << 4) | cid, 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &frame[..2], &mut crc)?;
+        frame[2] = crc[0];
+        frame[3] = crc[1];
+
+        let mut response = [0u8; MAX_FRAME_SIZE];
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &frame, &mut response, &mut valid_bits, 0, true)?;
+        let ats = Ats::parse(&response[..received - 2])?;
+
+        let sfgt = ats.sfgt_us();
+        if sfgt > 0 {
+            crate::delay::delay_us(sfgt);
+        }
+
+        self.iso_dep = Some(IsoDepParams {
+            fsc: (ats.fsc() as usize).min(MAX_FRAME_SIZE),
+            fwt_us: ats.fwt_us(),
+            cid: if ats.cid_supported { Some(cid) } else { None },
+            nad_supported: ats.nad_supported,
+            block_number: 0,
+            rats_cid: cid,
+        });
+        Ok(ats)
+    }
+
+    // Switches to the fastest bit rates the ATS offers, up to `max`, with PPS right after RATS.
+    // If the PICC does not confirm, both sides stay at 106 kbit/s. Returns the bit rates in use
+    // as (PCD to PICC, PICC to PCD).
+    pub fn pps<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        ats: &Ats,
+        max: BitRate,
+    ) -> Result<(BitRate, BitRate), RFIDError> {
+        let params = self.iso_dep.ok_or(RFIDError::InvalidResponse)?;
+        let (dr, ds) = ats.best_bit_rates(max);
+        if dr == BitRate::Kbps106 && ds == BitRate::Kbps106 {
+            return Ok((dr, ds));
+        }
+
+        let ppss = PICC_CMD_PPS | params.rats_cid;
+        let mut response = [0u8; 1];
+        match self.iso_dep_frame(serial, &[ppss, PPS0_PPS1, (ds.index() << 2) | dr.index()], &mut response) {
+            Ok(1) if response[0] == ppss => {
+                self.set_bit_rates(serial, dr, ds);
+                Ok((dr, ds))
+            }
+            Ok(_) | Err(RFIDError::Timeout | RFIDError::CrcError | RFIDError::CommunicationError | RFIDError::NoRoom) => {
+                self.set_bit_rates(serial, BitRate::Kbps106, BitRate::Kbps106);
+                Ok((BitRate::Kbps106, BitRate::Kbps106))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    // Programs TxSpeed, RxSpeed and the modulation width. CRC stays in software.
+    pub fn set_bit_rates<W: ufmt::uWrite>(&mut self, serial: &mut W, tx: BitRate, rx: BitRate) {
+        self.write_register(serial, TX_MODE_REG, tx.index() << 4);
+        self.write_register(serial, RX_MODE_REG, rx.index() << 4);
+        self.write_register(serial, MODE_WIDTH_REG, tx.mod_width());
+    }
+
+    // Parameters from the last RATS, None while the PICC is not activated
+    pub fn iso_dep_params(&self) -> Option<&IsoDepParams> {
+        self.iso_dep.as_ref()
+    }
+
+    // Sends one ISO-DEP block (PCB onwards, without CRC) and returns the length of the answer
+    // in `response`, CRC removed. The frame must fit the FSC and the PICC has FWT to answer.
+    pub fn iso_dep_frame<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        block: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let params = self.iso_dep.ok_or(RFIDError::InvalidResponse)?;
+        self.iso_dep_frame_timed(serial, block, response, params.fwt_us)
+    }
+
+    // Sends an APDU (or any INF payload) with the half-duplex block protocol and returns the
+    // length of the answer in `response`. Commands longer than the FSC are chained, chained
+    // answers are acknowledged and joined, waiting time extensions are granted, and lost or
+    // corrupted blocks are recovered with R(NAK) and retransmission.
+    pub fn iso_dep_transceive<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        command: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let mut params = self.iso_dep.ok_or(RFIDError::InvalidResponse)?;
+        let result = self.iso_dep_chain(serial, &mut params, command, response);
+        self.iso_dep = Some(params);
+        result
+    }
+
+    // Ends the ISO-DEP session with S(DESELECT), the PICC goes to HALT
+    pub fn iso_dep_deselect<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<(), RFIDError> {
+        let params = self.iso_dep.ok_or(RFIDError::InvalidResponse)?;
+        let (request, length) = params.prologue(PCB_S_DESELECT);
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let mut result = Err(RFIDError::Timeout);
+        for _ in 0..=MAX_RETRIES {
+            result = match self.iso_dep_frame(serial, &request[..length], &mut frame) {
+                Ok(received) => match Block::parse(&frame[..received]) {
+                    Some((Block::Deselect, _)) => Ok(()),
+                    _ => Err(RFIDError::InvalidResponse),
+                },
+                Err(error) => Err(error),
+            };
+            if result.is_ok() {
+                break;
+            }
+        }
+        self.iso_dep = None;
+        self.set_bit_rates(serial, BitRate::Kbps106, BitRate::Kbps106);
+        result
+    }
+
+    fn iso_dep_chain<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        params: &mut IsoDepParams,
+        command: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        let (_, prologue_len) = params.prologue(0);
+        let chunk_len = params.fsc - prologue_len - 2;
+        let mut block = [0u8; MAX_FRAME_SIZE];
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+
+        // Command, one I-block per chunk, each but the last acknowledged with R(ACK)
+        let mut offset = 0;
+        let received = loop {
+            let end = (offset + chunk_len).min(command.len());
+            let chaining = end < command.len();
+            let pcb = PCB_I_BLOCK | params.block_number | if chaining { PCB_CHAINING } else { 0 };
+            let (prologue, _) = params.prologue(pcb);
+            block[..prologue_len].copy_from_slice(&prologue[..prologue_len]);
+            block[prologue_len..prologue_len + end - offset].copy_from_slice(&command[offset..end]);
+            let length = prologue_len + end - offset;
+
+            let received = self.iso_dep_block(serial, params, &block[..length], &mut frame)?;
+            if !chaining {
+                break received;
+            }
+            match Block::parse(&frame[..received]) {
+                Some((Block::RAck { block_number }, _)) if block_number == params.block_number => {
+                    params.block_number ^= 1;
+                    offset = end;
+                }
+                _ => return Err(RFIDError::InvalidResponse),
+            }
+        };
+
+        // Answer, acknowledging chained I-blocks until the last one
+        let mut received = received;
+        let mut length = 0;
+        loop {
+            let (chaining, inf) = match Block::parse(&frame[..received]) {
+                Some((Block::I { chaining, block_number }, inf)) if block_number == params.block_number => (chaining, inf),
+                _ => return Err(RFIDError::InvalidResponse),
+            };
+            params.block_number ^= 1;
+            let data = &frame[inf..received];
+            response
+                .get_mut(length..length + data.len())
+                .ok_or(RFIDError::NoRoom)?
+                .copy_from_slice(data);
+            length += data.len();
+            if !chaining {
+                return Ok(length);
+            }
+
+            let (ack, ack_len) = params.prologue(PCB_R_ACK | params.block_number);
+            received = self.iso_dep_block(serial, params, &ack[..ack_len], &mut frame)?;
+        }
+    }
+
+    // Sends an I-block or R(ACK) and returns the answer that is neither S(WTX) nor a request
+    // to retransmit. Transmission errors are answered with R(NAK), or by repeating the R(ACK)
+    // while the PICC is chaining.
+    fn iso_dep_block<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        params: &IsoDepParams,
+        block: &[u8],
+        frame: &mut [u8; MAX_FRAME_SIZE],
+    ) -> Result<usize, RFIDError> {
+        let is_ack = block[0] & !(PCB_CID | PCB_BLOCK_NUMBER) == PCB_R_ACK;
+        let (nak, nak_len) = params.prologue(PCB_R_NAK | params.block_number);
+        let mut reply = [0u8; 3];
+        let mut next = block;
+        let mut fwt = params.fwt_us;
+        let mut errors = 0;
+        loop {
+            let result = self.iso_dep_frame_timed(serial, next, frame, fwt);
+            fwt = params.fwt_us;
+            let parsed = match result {
+                Ok(received) => Block::parse(&frame[..received]).map(|(parsed, inf)| (parsed, inf, received)),
+                Err(RFIDError::Timeout | RFIDError::CrcError | RFIDError::CommunicationError | RFIDError::Collision) => None,
+                Err(error) => return Err(error),
+            };
+            match parsed {
+                Some((Block::WaitingTimeExtension(wtxm), _, _)) if (1..=59).contains(&wtxm) => {
+                    let (prologue, length) = params.prologue(PCB_S_WTX);
+                    reply[..length].copy_from_slice(&prologue[..length]);
+                    reply[length] = wtxm;
+                    next = &reply[..length + 1];
+                    fwt = (params.fwt_us * wtxm as u32).min(fwt_us(14));
+                    continue;
+                }
+                // The PICC missed our last block
+                Some((Block::RAck { block_number }, _, _)) if block_number != params.block_number => next = block,
+                Some((_, _, received)) => return Ok(received),
+                None => next = if is_ack { block } else { &nak[..nak_len] },
+            }
+            errors += 1;
+            if errors > MAX_RETRIES {
+                return Err(RFIDError::CommunicationError);
+            }
+        }
+    }
+
+    fn iso_dep_frame_timed<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        block: &[u8],
+        response: &mut [u8],
+        fwt_us: u32,
+    ) -> Result<usize, RFIDError> {
+        let params = self.iso_dep.ok_or(RFIDError::InvalidResponse)?;
+        if block.len() + 2 > params.fsc {
+            return Err(RFIDError::NoRoom);
+        }
+
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        frame[..block.len()].copy_from_slice(block);
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, block, &mut crc)?;
+        frame[block.len()..block.len() + 2].copy_from_slice(&crc);
+
+        let mut back = [0u8; MAX_FRAME_SIZE];
+        let mut valid_bits = 0;
+        self.set_timeout(serial, fwt_us);
+        let result = self.transceive_data(serial, &frame[..block.len() + 2], &mut back, &mut valid_bits, 0, true);
+        self.reset_timeout(serial);
+
+        let length = result? - 2;
+        response.get_mut(..length).ok_or(RFIDError::NoRoom)?.copy_from_slice(&back[..length]);
+        Ok(length)
+    }
+}
@@ -0,0 +1,159 @@
+// src/keys.rs
+// Per-card MIFARE Classic keys derived from a master key and the card UID,
+// so no two cards in a deployment share a sector key
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::{Aes128, Block};
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::Uid;
+use crate::errors::RFIDError;
+use crate::mifare::{self, Key, KeyType, MF_KEY_SIZE};
+use crate::rfid_rc522::RfidRc522;
+
+// Supplies the key for a sector of a given card
+pub trait KeyProvider {
+    // None if the provider has no key for this sector/key type
+    fn key_for(&mut self, uid: &Uid, sector: u8, key_type: KeyType) -> Option<Key>;
+}
+
+// The same key for every card and sector
+impl KeyProvider for Key {
+    fn key_for(&mut self, _uid: &Uid, _sector: u8, _key_type: KeyType) -> Option<Key> {
+        Some(*self)
+    }
+}
+
+// NXP AN10922 AES-128 diversification. The diversification input is
+// UID || sector || auth command (0x60/0x61) || system identifier, and the
+// Classic key is the first 6 bytes of the diversified AES key.
+pub struct An10922Diversifier<'a> {
+    master_key: [u8; 16],
+    system_identifier: &'a [u8],
+}
+
+impl<'a> An10922Diversifier<'a> {
+    // `system_identifier` is optional deployment specific data (e.g. the application name),
+    // at most 19 bytes so the input fits the 31 byte limit with a 10 byte UID
+    pub fn new(master_key: [u8; 16], system_identifier: &'a [u8]) -> Self {
+        An10922Diversifier { master_key, system_identifier }
+    }
+}
+
+impl KeyProvider for An10922Diversifier<'_> {
+    fn key_for(&mut self, uid: &Uid, sector: u8, key_type: KeyType) -> Option<Key> {
+        let mut input = [0u8; 31];
+        let mut len = 0;
+        for &byte in uid
+            .as_bytes()
+            .iter()
+            .chain([sector, key_type.auth_command()].iter())
+            .chain(self.system_identifier.iter())
+        {
+            *input.get_mut(len)? = byte;
+            len += 1;
+        }
+
+        let diversified = an10922_aes128(&self.master_key, &input[..len])?;
+        let mut key = Key([0u8; MF_KEY_SIZE]);
+        key.0.copy_from_slice(&diversified[..MF_KEY_SIZE]);
+        Some(key)
+    }
+}
+
+// Simple derivation: the base key XORed with the UID bytes. Only hides the shared
+// key from casual inspection, use An10922Diversifier where cards must not reveal it.
+pub struct UidXorKeys {
+    pub base_key: Key,
+}
+
+impl KeyProvider for UidXorKeys {
+    fn key_for(&mut self, uid: &Uid, sector: u8, _key_type: KeyType) -> Option<Key> {
+        let uid = uid.as_bytes();
+        if uid.is_empty() {
+            return None;
+        }
+        let mut key = self.base_key;
+        for (i, byte) in key.0.iter_mut().enumerate() {
+            *byte ^= uid[i % uid.len()];
+        }
+        key.0[MF_KEY_SIZE - 1] ^= sector;
+        Some(key)
+    }
+}
+
+// AN10922 AES-128 key diversification: CMAC over 0x01 || input, always padded to 32 bytes.
+// `input` must be 1 to 31 bytes long.
+pub fn an10922_aes128(master_key: &[u8; 16], input: &[u8]) -> Option<[u8; 16]> {
+    if input.is_empty() || input.len() > 31 {
+        return None;
+    }
+    let cipher = Aes128::new(master_key.into());
+
+    let mut data = [0u8; 32];
+    data[0] = 0x01; // Diversification constant for AES-128 keys
+    data[1..=input.len()].copy_from_slice(input);
+    let padded = input.len() < 31;
+    if padded {
+        data[input.len() + 1] = 0x80;
+    }
+
+    let (k1, k2) = cmac_subkeys(&cipher);
+    let subkey = if padded { k2 } else { k1 };
+    for (byte, k) in data[16..].iter_mut().zip(subkey.iter()) {
+        *byte ^= k;
+    }
+
+    // AES-CBC with a zero IV, the last block is the diversified key
+    let mut block = Block::clone_from_slice(&data[..16]);
+    cipher.encrypt_block(&mut block);
+    for (byte, d) in block.iter_mut().zip(data[16..].iter()) {
+        *byte ^= d;
+    }
+    cipher.encrypt_block(&mut block);
+    Some(block.into())
+}
+
+// CMAC subkeys K1 and K2 (NIST SP 800-38B)
+fn cmac_subkeys(cipher: &Aes128) -> ([u8; 16], [u8; 16]) {
+    let mut l = Block::default();
+    cipher.encrypt_block(&mut l);
+    let k1 = shift_left_xor(&l.into());
+    let k2 = shift_left_xor(&k1);
+    (k1, k2)
+}
+
+fn shift_left_xor(input: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    for i in 0..16 {
+        out[i] = input[i] << 1;
+        if i < 15 {
+            out[i] |= input[i + 1] >> 7;
+        }
+    }
+    if input[0] & 0x80 != 0 {
+        out[15] ^= 0x87;
+    }
+    out
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Authenticates the sector containing `block_addr` with the key the provider gives for this card
+    pub fn mifare_authenticate_with<W: ufmt::uWrite, P: KeyProvider>(
+        &mut self,
+        serial: &mut W,
+        provider: &mut P,
+        key_type: KeyType,
+        block_addr: u8,
+        uid: &Uid,
+    ) -> Result<(), RFIDError> {
+        let key = provider
+            .key_for(uid, mifare::block_sector(block_addr), key_type)
+            .ok_or(RFIDError::AuthenticationFailed)?;
+        self.mifare_authenticate(serial, key_type, block_addr, &key, uid)
+    }
+}
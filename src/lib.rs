@@ -1,10 +1,39 @@
 #![no_std]
 // src/lib.rs
 
+#[cfg(feature = "std")]
+extern crate std;
+
+mod delay;
 pub mod registers;
 pub mod commands;
 pub mod rfid_rc522;
 pub mod card_types;
 pub mod errors;
+pub mod mifare;
+pub mod dump;
+pub mod crypto1;
+pub mod keys;
+pub mod magic;
+pub mod ultralight;
+pub mod ultralight_c;
+pub mod lock_bits;
+pub mod ndef;
+pub mod type2;
+pub mod mad;
+pub mod classic_ndef;
+pub mod format;
+pub mod ntag;
+pub mod originality;
+pub mod iso14443_4;
+pub mod apdu;
+pub mod desfire;
+pub mod desfire_crypto;
+pub mod type4;
+pub mod ntag424;
+pub mod inventory;
+pub mod presence;
+#[cfg(feature = "std")]
+pub mod dump_formats;
 
 pub use rfid_rc522::RfidRc522;
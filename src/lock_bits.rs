@@ -0,0 +1,324 @@
+// src/lock_bits.rs
+// Static/dynamic lock bytes and the OTP page of NFC Forum Type 2 Tags (Ultralight, NTAG).
+// Lock and OTP bits can only ever be set, so every write goes through a report of what it
+// would change, and can be run as a dry run to audit it first.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::CardType;
+use crate::errors::RFIDError;
+use crate::rfid_rc522::RfidRc522;
+use crate::ultralight::UL_PAGE_SIZE;
+
+pub const STATIC_LOCK_PAGE: u8 = 2;
+pub const OTP_PAGE: u8 = 3;
+
+// First page covered by dynamic lock bits
+const DYNAMIC_LOCK_FIRST_PAGE: u8 = 16;
+
+// Where the dynamic lock bytes are and how many pages each bit locks
+#[derive(Clone, Copy, PartialEq)]
+pub struct DynamicLockLayout {
+    pub page: u8,           // Page holding the 3 dynamic lock bytes
+    pub lock_bits: u8,      // Number of page lock bits, the remaining bits of byte 2 are block-lock bits
+    pub pages_per_bit: u8,
+    pub pages_per_block_lock: u8, // Pages whose lock bits one block-lock bit freezes
+}
+
+impl DynamicLockLayout {
+    // NTAG213 and the NFC Forum default lock 2 pages per bit, NTAG215/216 16 pages.
+    // A block-lock bit covers 16 pages on the small tags, 32 on NTAG215/216.
+    // Tags with only 16 pages have no dynamic lock bytes.
+    pub fn for_card(card_type: &CardType) -> Option<Self> {
+        let (page, lock_bits, pages_per_bit, pages_per_block_lock) = match card_type {
+            CardType::MifareUltralightEv1Mf0ul21 | CardType::Ntag212 => (0x24, 10, 2, 16),
+            CardType::Ntag213 => (0x28, 12, 2, 16),
+            CardType::Ntag215 => (0x82, 8, 16, 32),
+            CardType::Ntag216 => (0xE2, 14, 16, 32),
+            _ => return None,
+        };
+        Some(DynamicLockLayout { page, lock_bits, pages_per_bit, pages_per_block_lock })
+    }
+
+    // Page lock bit covering `page`, as (byte, bit)
+    fn bit_for_page(&self, page: u8) -> Option<(usize, u8)> {
+        if page < DYNAMIC_LOCK_FIRST_PAGE || page >= self.page {
+            return None;
+        }
+        let index = (page - DYNAMIC_LOCK_FIRST_PAGE) / self.pages_per_bit;
+        if index >= self.lock_bits {
+            return None;
+        }
+        Some((index as usize / 8, index % 8))
+    }
+}
+
+// A set of page numbers
+#[derive(Clone, Copy, PartialEq)]
+pub struct PageSet {
+    bits: [u8; 32],
+}
+
+impl PageSet {
+    pub const fn new() -> Self {
+        PageSet { bits: [0u8; 32] }
+    }
+
+    pub fn insert(&mut self, page: u8) {
+        self.bits[page as usize / 8] |= 1 << (page % 8);
+    }
+
+    pub fn contains(&self, page: u8) -> bool {
+        self.bits[page as usize / 8] & (1 << (page % 8)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&byte| byte == 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(move |&page| self.contains(page))
+    }
+}
+
+impl Default for PageSet {
+    fn default() -> Self {
+        PageSet::new()
+    }
+}
+
+// Lock bytes as stored on the tag, or as bits to be set
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct LockBytes {
+    pub static_lock: [u8; 2],  // Bytes 2 and 3 of page 2
+    pub dynamic_lock: [u8; 3], // Unused when the tag has no dynamic lock bytes
+}
+
+// Interpretation of the lock bytes for a given tag layout
+#[derive(Clone, Copy, PartialEq)]
+pub struct LockState {
+    pub bytes: LockBytes,
+    pub layout: Option<DynamicLockLayout>,
+}
+
+impl LockState {
+    pub fn new(bytes: LockBytes, layout: Option<DynamicLockLayout>) -> Self {
+        LockState { bytes, layout }
+    }
+
+    // Whether `page` is read-only because of a lock bit
+    pub fn is_page_locked(&self, page: u8) -> bool {
+        let [lock0, lock1] = self.bytes.static_lock;
+        match page {
+            OTP_PAGE => lock0 & 0x08 != 0,
+            4..=7 => lock0 & (1 << page) != 0,
+            8..=15 => lock1 & (1 << (page - 8)) != 0,
+            _ => match self.layout.and_then(|layout| layout.bit_for_page(page)) {
+                Some((byte, bit)) => self.bytes.dynamic_lock[byte] & (1 << bit) != 0,
+                None => false,
+            },
+        }
+    }
+
+    // Pages made read-only by the lock bits
+    pub fn locked_pages(&self) -> PageSet {
+        let mut pages = PageSet::new();
+        for page in 0..=255u8 {
+            if self.is_page_locked(page) {
+                pages.insert(page);
+            }
+        }
+        pages
+    }
+
+    // Block-lock bits: once set, the lock bits they cover can no longer be changed.
+    // Bits 0-2 are the static ones (OTP, pages 4-9, pages 10-15), bits 8-15 dynamic lock byte 2.
+    pub fn block_lock_bits(&self) -> u16 {
+        let dynamic = match self.layout {
+            Some(_) => self.bytes.dynamic_lock[2],
+            None => 0,
+        };
+        (self.bytes.static_lock[0] & 0x07) as u16 | (dynamic as u16) << 8
+    }
+
+    // Lock bits that set block-lock bits freeze: the tag ignores writes to them
+    pub fn frozen_bits(&self) -> LockBytes {
+        let mut frozen = LockBytes::default();
+        let lock0 = self.bytes.static_lock[0];
+        if lock0 & 0x01 != 0 {
+            // BL-OTP: the OTP lock bit
+            frozen.static_lock[0] |= 0x08;
+        }
+        if lock0 & 0x02 != 0 {
+            // BL 9-4: lock bits of pages 4-9
+            frozen.static_lock[0] |= 0xF0;
+            frozen.static_lock[1] |= 0x03;
+        }
+        if lock0 & 0x04 != 0 {
+            // BL 15-10: lock bits of pages 10-15
+            frozen.static_lock[1] |= 0xFC;
+        }
+        if let Some(layout) = self.layout {
+            let bits_per_block_lock = layout.pages_per_block_lock / layout.pages_per_bit;
+            for block_lock in 0..8u8 {
+                if self.bytes.dynamic_lock[2] & (1 << block_lock) == 0 {
+                    continue;
+                }
+                let first = block_lock.saturating_mul(bits_per_block_lock).min(layout.lock_bits);
+                let last = first.saturating_add(bits_per_block_lock).min(layout.lock_bits);
+                for index in first..last {
+                    frozen.dynamic_lock[index as usize / 8] |= 1 << (index % 8);
+                }
+            }
+        }
+        frozen
+    }
+
+    // State after setting the bits of `request`. Bits frozen by block-lock bits stay as they
+    // are, like on the tag.
+    pub fn with(&self, request: &LockBytes) -> LockState {
+        let frozen = self.frozen_bits();
+        let mut bytes = self.bytes;
+        for ((byte, set), frozen) in bytes
+            .static_lock
+            .iter_mut()
+            .zip(request.static_lock.iter())
+            .zip(frozen.static_lock)
+        {
+            *byte |= set & !frozen;
+        }
+        if self.layout.is_some() {
+            for ((byte, set), frozen) in bytes
+                .dynamic_lock
+                .iter_mut()
+                .zip(request.dynamic_lock.iter())
+                .zip(frozen.dynamic_lock)
+            {
+                *byte |= set & !frozen;
+            }
+        }
+        LockState { bytes, layout: self.layout }
+    }
+}
+
+// What a lock operation changes (or, in a dry run, would change)
+#[derive(Clone, Copy, PartialEq)]
+pub struct LockReport {
+    pub newly_locked: PageSet,    // Pages that become read-only
+    pub blocked: PageSet,         // Requested pages that stay writable, their lock bits are frozen
+    pub newly_block_locked: u16,  // Block-lock bits that become set, see LockState::block_lock_bits
+    pub result: LockState,
+    pub written: bool,            // false for a dry run or when nothing changes
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    pub fn type2_read_locks<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+    ) -> Result<LockState, RFIDError> {
+        let layout = DynamicLockLayout::for_card(card_type);
+        let mut bytes = LockBytes::default();
+
+        let data = self.ultralight_read(serial, STATIC_LOCK_PAGE)?;
+        bytes.static_lock.copy_from_slice(&data[2..4]);
+        if let Some(layout) = layout {
+            let data = self.ultralight_read(serial, layout.page)?;
+            bytes.dynamic_lock.copy_from_slice(&data[..3]);
+        }
+        Ok(LockState::new(bytes, layout))
+    }
+
+    // Sets the lock bits in `request` (bits already set on the tag are ignored, bits frozen by a
+    // block-lock bit are reported in `blocked`). With `dry_run` nothing is written and the report
+    // shows what the write would do. This is irreversible.
+    pub fn type2_set_locks<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+        request: &LockBytes,
+        dry_run: bool,
+    ) -> Result<LockReport, RFIDError> {
+        let current = self.type2_read_locks(serial, card_type)?;
+        if current.layout.is_none() && request.dynamic_lock != [0u8; 3] {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let result = current.with(request);
+
+        let mut newly_locked = PageSet::new();
+        for page in result.locked_pages().iter() {
+            if !current.is_page_locked(page) {
+                newly_locked.insert(page);
+            }
+        }
+        // Requested lock bits that neither are set nor can be, as pages
+        let frozen = current.frozen_bits();
+        let mut blocked_bits = LockBytes::default();
+        for (i, byte) in blocked_bits.static_lock.iter_mut().enumerate() {
+            *byte = request.static_lock[i] & frozen.static_lock[i] & !current.bytes.static_lock[i];
+        }
+        for (i, byte) in blocked_bits.dynamic_lock.iter_mut().enumerate() {
+            *byte = request.dynamic_lock[i] & frozen.dynamic_lock[i] & !current.bytes.dynamic_lock[i];
+        }
+        let blocked = LockState::new(blocked_bits, current.layout).locked_pages();
+
+        let mut report = LockReport {
+            newly_locked,
+            blocked,
+            newly_block_locked: result.block_lock_bits() & !current.block_lock_bits(),
+            result,
+            written: false,
+        };
+        if dry_run || result == current {
+            return Ok(report);
+        }
+
+        // Dynamic bits first, static block-lock bits do not cover them. The RFUI byte must be 0.
+        if let Some(layout) = result.layout {
+            if result.bytes.dynamic_lock != current.bytes.dynamic_lock {
+                let [b0, b1, b2] = result.bytes.dynamic_lock;
+                self.ultralight_write(serial, layout.page, &[b0, b1, b2, 0x00])?;
+            }
+        }
+        // The tag ignores bytes 0 and 1 of page 2 and ORs bytes 2 and 3 into the lock bytes
+        if result.bytes.static_lock != current.bytes.static_lock {
+            let [lock0, lock1] = result.bytes.static_lock;
+            self.ultralight_write(serial, STATIC_LOCK_PAGE, &[0x00, 0x00, lock0, lock1])?;
+        }
+        report.written = true;
+        Ok(report)
+    }
+
+    pub fn type2_read_otp<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<[u8; UL_PAGE_SIZE], RFIDError> {
+        let data = self.ultralight_read(serial, OTP_PAGE)?;
+        let mut otp = [0u8; UL_PAGE_SIZE];
+        otp.copy_from_slice(&data[..UL_PAGE_SIZE]);
+        Ok(otp)
+    }
+
+    // Sets bits of the OTP page (the tag ORs them in). Returns the resulting value, with
+    // `dry_run` without writing. On NDEF formatted tags page 3 is the Capability Container.
+    pub fn type2_write_otp<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        bits: &[u8; UL_PAGE_SIZE],
+        dry_run: bool,
+    ) -> Result<[u8; UL_PAGE_SIZE], RFIDError> {
+        let mut otp = self.type2_read_otp(serial)?;
+        for (byte, set) in otp.iter_mut().zip(bits.iter()) {
+            *byte |= set;
+        }
+        if !dry_run {
+            self.ultralight_write(serial, OTP_PAGE, bits)?;
+        }
+        Ok(otp)
+    }
+}
@@ -0,0 +1,269 @@
+// src/mad.rs
+// MIFARE Application Directory (NXP AN10787). MAD1 lives in blocks 1-2 of sector 0 and covers
+// sectors 1-15, MAD2 (4K cards) adds blocks 64-66 of sector 16 for sectors 17-39.
+// Each sector gets a 2 byte application identifier, stored little endian.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::{CardType, Uid};
+use crate::dump::MAX_SECTORS;
+use crate::errors::RFIDError;
+use crate::mifare::{self, Key, KeyType, MF_BLOCK_SIZE, MF_KEY_SIZE};
+use crate::rfid_rc522::RfidRc522;
+
+// Public key A of the MAD sectors
+pub const MAD_KEY_A: Key = Key([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]);
+
+pub const MAD2_SECTOR: u8 = 16;
+
+// Application identifiers
+pub const MAD_AID_FREE: u16 = 0x0000;
+pub const MAD_AID_DEFECT: u16 = 0x0001;
+pub const MAD_AID_RESERVED: u16 = 0x0002;
+pub const MAD_AID_CARD_HOLDER: u16 = 0x0004;
+pub const MAD_AID_NDEF: u16 = 0xE103; // NFC Forum cluster 0xE1, application 0x03
+
+// Access bits of the MAD sectors: data blocks read with key A or B, written with key B
+pub const MAD_ACCESS_BITS: [u8; 3] = [0x78, 0x77, 0x88];
+
+// General purpose byte of sector 0: DA (MAD available), MA (multi-application card), MAD version
+const GPB_DA: u8 = 0x80;
+const GPB_MA: u8 = 0x40;
+const GPB_VERSION_MASK: u8 = 0x03;
+
+// CRC preset and polynomial x^8 + x^4 + x^3 + x^2 + 1
+const MAD_CRC_PRESET: u8 = 0xC7;
+const MAD_CRC_POLY: u8 = 0x1D;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MadVersion {
+    Mad1,
+    Mad2,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Mad {
+    pub version: MadVersion,
+    pub info: u8,  // Sector of the card publisher, 0 for none (MAD1)
+    pub info2: u8, // Same for MAD2
+    aids: [u16; MAX_SECTORS],
+}
+
+impl Mad {
+    // An empty directory sized for the card: MAD2 for 4K cards, MAD1 otherwise
+    pub fn new(card_type: &CardType) -> Option<Self> {
+        let version = match card_type {
+            CardType::MifareMini | CardType::Mifare1K => MadVersion::Mad1,
+            CardType::Mifare4K => MadVersion::Mad2,
+            _ => return None,
+        };
+        Some(Mad { version, info: 0, info2: 0, aids: [MAD_AID_FREE; MAX_SECTORS] })
+    }
+
+    // Number of sectors the directory covers, including the MAD sectors themselves
+    pub fn sector_count(&self) -> u8 {
+        match self.version {
+            MadVersion::Mad1 => 16,
+            MadVersion::Mad2 => MAX_SECTORS as u8,
+        }
+    }
+
+    pub fn is_mad_sector(&self, sector: u8) -> bool {
+        sector == 0 || (sector == MAD2_SECTOR && self.version == MadVersion::Mad2)
+    }
+
+    pub fn aid(&self, sector: u8) -> u16 {
+        if self.is_mad_sector(sector) || sector >= self.sector_count() {
+            return MAD_AID_RESERVED;
+        }
+        self.aids[sector as usize]
+    }
+
+    pub fn set_aid(&mut self, sector: u8, aid: u16) -> Result<(), RFIDError> {
+        if self.is_mad_sector(sector) || sector >= self.sector_count() {
+            return Err(RFIDError::InvalidResponse);
+        }
+        self.aids[sector as usize] = aid;
+        Ok(())
+    }
+
+    // Sectors assigned to `aid`, in ascending order
+    pub fn sectors(&self, aid: u16) -> impl Iterator<Item = u8> + '_ {
+        (1..self.sector_count()).filter(move |&sector| self.aid(sector) == aid)
+    }
+
+    // Parses blocks 1 and 2 of sector 0, checking the CRC
+    pub fn parse_mad1(blocks: &[[u8; MF_BLOCK_SIZE]; 2]) -> Result<Self, RFIDError> {
+        let mut data = [0u8; 2 * MF_BLOCK_SIZE];
+        data[..MF_BLOCK_SIZE].copy_from_slice(&blocks[0]);
+        data[MF_BLOCK_SIZE..].copy_from_slice(&blocks[1]);
+        if mad_crc8(&data[1..]) != data[0] {
+            return Err(RFIDError::CrcError);
+        }
+
+        let mut mad = Mad {
+            version: MadVersion::Mad1,
+            info: data[1] & 0x3F,
+            info2: 0,
+            aids: [MAD_AID_FREE; MAX_SECTORS],
+        };
+        for (sector, aid) in data[2..].chunks_exact(2).enumerate() {
+            mad.aids[sector + 1] = u16::from_le_bytes([aid[0], aid[1]]);
+        }
+        Ok(mad)
+    }
+
+    // Adds the MAD2 directory from blocks 64-66, checking the CRC
+    pub fn parse_mad2(&mut self, blocks: &[[u8; MF_BLOCK_SIZE]; 3]) -> Result<(), RFIDError> {
+        let mut data = [0u8; 3 * MF_BLOCK_SIZE];
+        for (chunk, block) in data.chunks_exact_mut(MF_BLOCK_SIZE).zip(blocks.iter()) {
+            chunk.copy_from_slice(block);
+        }
+        if mad_crc8(&data[1..]) != data[0] {
+            return Err(RFIDError::CrcError);
+        }
+
+        self.version = MadVersion::Mad2;
+        self.info2 = data[1] & 0x3F;
+        for (i, aid) in data[2..].chunks_exact(2).enumerate() {
+            self.aids[MAD2_SECTOR as usize + 1 + i] = u16::from_le_bytes([aid[0], aid[1]]);
+        }
+        Ok(())
+    }
+
+    pub fn mad1_blocks(&self) -> [[u8; MF_BLOCK_SIZE]; 2] {
+        let mut data = [0u8; 2 * MF_BLOCK_SIZE];
+        data[1] = self.info;
+        for (sector, aid) in data[2..].chunks_exact_mut(2).enumerate() {
+            aid.copy_from_slice(&self.aids[sector + 1].to_le_bytes());
+        }
+        data[0] = mad_crc8(&data[1..]);
+
+        let mut blocks = [[0u8; MF_BLOCK_SIZE]; 2];
+        for (block, chunk) in blocks.iter_mut().zip(data.chunks_exact(MF_BLOCK_SIZE)) {
+            block.copy_from_slice(chunk);
+        }
+        blocks
+    }
+
+    pub fn mad2_blocks(&self) -> [[u8; MF_BLOCK_SIZE]; 3] {
+        let mut data = [0u8; 3 * MF_BLOCK_SIZE];
+        data[1] = self.info2;
+        for (i, aid) in data[2..].chunks_exact_mut(2).enumerate() {
+            aid.copy_from_slice(&self.aids[MAD2_SECTOR as usize + 1 + i].to_le_bytes());
+        }
+        data[0] = mad_crc8(&data[1..]);
+
+        let mut blocks = [[0u8; MF_BLOCK_SIZE]; 3];
+        for (block, chunk) in blocks.iter_mut().zip(data.chunks_exact(MF_BLOCK_SIZE)) {
+            block.copy_from_slice(chunk);
+        }
+        blocks
+    }
+
+    // General purpose byte for the sector 0 trailer
+    pub fn gpb(&self) -> u8 {
+        let version = match self.version {
+            MadVersion::Mad1 => 0x01,
+            MadVersion::Mad2 => 0x02,
+        };
+        GPB_DA | GPB_MA | version
+    }
+}
+
+// CRC-8 over the info byte and the AIDs of a MAD
+pub fn mad_crc8(data: &[u8]) -> u8 {
+    let mut crc = MAD_CRC_PRESET;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ MAD_CRC_POLY } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Sector trailer: key A | access bits | general purpose byte | key B
+pub fn trailer_bytes(key_a: &Key, access_bits: &[u8; 3], gpb: u8, key_b: &Key) -> [u8; MF_BLOCK_SIZE] {
+    let mut trailer = [0u8; MF_BLOCK_SIZE];
+    trailer[..MF_KEY_SIZE].copy_from_slice(&key_a.0);
+    trailer[6..9].copy_from_slice(access_bits);
+    trailer[9] = gpb;
+    trailer[10..].copy_from_slice(&key_b.0);
+    trailer
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Reads MAD1 and, if the general purpose byte announces it, MAD2 with the public MAD key.
+    // The PICC is halted at the end, reselect it before sending further commands.
+    pub fn read_mad<W: ufmt::uWrite>(&mut self, serial: &mut W, uid: &Uid) -> Result<Mad, RFIDError> {
+        let result = self.read_mad_blocks(serial, uid);
+        // HLTA goes out encrypted, stop_crypto1 alone would leave the PICC authenticated
+        self.halt_a(serial).ok();
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Writes the directory, authenticating the MAD sectors with `key_b`. The PICC is halted at
+    // the end like with read_mad.
+    pub fn write_mad<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+        key_b: &Key,
+    ) -> Result<(), RFIDError> {
+        let result = self.write_mad_blocks(serial, uid, mad, KeyType::B, key_b);
+        self.halt_a(serial).ok();
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Leaves the PICC authenticated, callers continue with nested authentication
+    pub(crate) fn read_mad_blocks<W: ufmt::uWrite>(&mut self, serial: &mut W, uid: &Uid) -> Result<Mad, RFIDError> {
+        self.mifare_authenticate(serial, KeyType::A, mifare::sector_trailer(0), &MAD_KEY_A, uid)?;
+        let gpb = self.mifare_read(serial, mifare::sector_trailer(0))?[9];
+        if gpb & GPB_DA == 0 {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+        let mut mad = Mad::parse_mad1(&[self.mifare_read(serial, 1)?, self.mifare_read(serial, 2)?])?;
+
+        if gpb & GPB_VERSION_MASK == 0x02 {
+            let first = mifare::sector_first_block(MAD2_SECTOR);
+            self.mifare_authenticate(serial, KeyType::A, first, &MAD_KEY_A, uid)?;
+            let blocks = [
+                self.mifare_read(serial, first)?,
+                self.mifare_read(serial, first + 1)?,
+                self.mifare_read(serial, first + 2)?,
+            ];
+            mad.parse_mad2(&blocks)?;
+        }
+        Ok(mad)
+    }
+
+    pub(crate) fn write_mad_blocks<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &Uid,
+        mad: &Mad,
+        key_type: KeyType,
+        key: &Key,
+    ) -> Result<(), RFIDError> {
+        self.mifare_authenticate(serial, key_type, mifare::sector_trailer(0), key, uid)?;
+        for (i, block) in mad.mad1_blocks().iter().enumerate() {
+            self.mifare_write(serial, 1 + i as u8, block)?;
+        }
+        if mad.version == MadVersion::Mad2 {
+            let first = mifare::sector_first_block(MAD2_SECTOR);
+            self.mifare_authenticate(serial, key_type, first, key, uid)?;
+            for (i, block) in mad.mad2_blocks().iter().enumerate() {
+                self.mifare_write(serial, first + i as u8, block)?;
+            }
+        }
+        Ok(())
+    }
+}
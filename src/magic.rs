@@ -0,0 +1,169 @@
+// src/magic.rs
+// "Chinese magic" MIFARE Classic cards with a writable block 0.
+// Gen1a cards open a backdoor after the 0x40/0x43 unlock sequence and accept reads and
+// writes without authentication. Gen2 (CUID) cards accept normal authenticated writes to block 0.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::Uid;
+use crate::errors::RFIDError;
+use crate::mifare::{Key, KeyType, MF_BLOCK_SIZE};
+use crate::registers::{PICC_CMD_MAGIC_WUPC1, PICC_CMD_MAGIC_WUPC2};
+use crate::rfid_rc522::RfidRc522;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MagicGeneration {
+    Gen1a,
+    Gen2,
+}
+
+// Manufacturer block of a card with a 4 byte UID:
+// UID (4) | BCC | SAK | ATQA (2) | manufacturer data (8)
+#[derive(Clone, Copy, PartialEq)]
+pub struct Block0 {
+    pub uid: [u8; 4],
+    pub sak: u8,
+    pub atqa: [u8; 2],
+    pub manufacturer_data: [u8; 8],
+}
+
+impl Block0 {
+    // Parses a block 0 read from a card, rejecting it if the BCC is wrong
+    pub fn parse(block: &[u8; MF_BLOCK_SIZE]) -> Result<Self, RFIDError> {
+        validate_block0(block)?;
+        let mut block0 = Block0 {
+            uid: [0u8; 4],
+            sak: block[5],
+            atqa: [block[6], block[7]],
+            manufacturer_data: [0u8; 8],
+        };
+        block0.uid.copy_from_slice(&block[..4]);
+        block0.manufacturer_data.copy_from_slice(&block[8..]);
+        Ok(block0)
+    }
+
+    // Block contents with the BCC recomputed from the UID
+    pub fn to_bytes(&self) -> [u8; MF_BLOCK_SIZE] {
+        let mut block = [0u8; MF_BLOCK_SIZE];
+        block[..4].copy_from_slice(&self.uid);
+        block[4] = bcc(&self.uid);
+        block[5] = self.sak;
+        block[6..8].copy_from_slice(&self.atqa);
+        block[8..].copy_from_slice(&self.manufacturer_data);
+        block
+    }
+}
+
+// Block Check Character, the XOR of the UID bytes
+pub fn bcc(uid: &[u8]) -> u8 {
+    uid.iter().fold(0, |acc, byte| acc ^ byte)
+}
+
+// A wrong BCC or a SAK with the cascade bit set makes the card unselectable, and on
+// Gen2 cards there is no way to recover from that
+pub fn validate_block0(block: &[u8; MF_BLOCK_SIZE]) -> Result<(), RFIDError> {
+    if block[4] != bcc(&block[..4]) {
+        return Err(RFIDError::InvalidBlock0);
+    }
+    if block[5] & 0x04 != 0 {
+        return Err(RFIDError::InvalidBlock0);
+    }
+    Ok(())
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Sends the Gen1a unlock sequence. On success block reads and writes work without
+    // authentication until the card is halted or leaves the field.
+    pub fn magic_open_backdoor<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<(), RFIDError> {
+        self.stop_crypto1(serial);
+        self.halt_a(serial)?;
+
+        // 0x40 as a 7 bit short frame, then 0x43 as a full byte, both without CRC
+        for (command, valid_bits) in [(PICC_CMD_MAGIC_WUPC1, 7), (PICC_CMD_MAGIC_WUPC2, 0)] {
+            let mut response = [0u8; 1];
+            let mut valid_bits = valid_bits;
+            let received = self.transceive_data(serial, &[command], &mut response, &mut valid_bits, 0, false)?;
+            if received != 1 || valid_bits != 4 || response[0] & 0x0F != 0x0A {
+                return Err(RFIDError::InvalidResponse);
+            }
+        }
+        Ok(())
+    }
+
+    // Checks whether the card answers the Gen1a unlock sequence. The card is left halted
+    // (or in backdoor mode), select it again before normal use.
+    pub fn magic_detect_gen1a<W: ufmt::uWrite>(&mut self, serial: &mut W) -> bool {
+        self.magic_open_backdoor(serial).is_ok()
+    }
+
+    // Reads block 0 of a magic card. `uid` and `key` (key A of sector 0) are only used for Gen2 cards.
+    pub fn magic_read_block0<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        generation: MagicGeneration,
+        uid: &Uid,
+        key: &Key,
+    ) -> Result<Block0, RFIDError> {
+        self.magic_unlock(serial, generation, uid, key)?;
+        let block = self.mifare_read(serial, 0);
+        self.stop_crypto1(serial);
+        Block0::parse(&block?)
+    }
+
+    // Writes block 0 of a magic card. `uid` and `key` (key A of sector 0) are only used for Gen2 cards.
+    pub fn magic_write_block0<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        generation: MagicGeneration,
+        uid: &Uid,
+        key: &Key,
+        block0: &Block0,
+    ) -> Result<(), RFIDError> {
+        let data = block0.to_bytes();
+        validate_block0(&data)?;
+
+        self.magic_unlock(serial, generation, uid, key)?;
+        let result = self.mifare_write(serial, 0, &data);
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Changes the UID of a magic card, keeping SAK, ATQA and manufacturer data of the current block 0
+    pub fn magic_set_uid<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        generation: MagicGeneration,
+        uid: &Uid,
+        key: &Key,
+        new_uid: &[u8; 4],
+    ) -> Result<(), RFIDError> {
+        self.magic_unlock(serial, generation, uid, key)?;
+        let result = self.mifare_read(serial, 0).and_then(|block| {
+            let mut block0 = Block0::parse(&block)?;
+            block0.uid = *new_uid;
+            let data = block0.to_bytes();
+            validate_block0(&data)?;
+            self.mifare_write(serial, 0, &data)
+        });
+        self.stop_crypto1(serial);
+        result
+    }
+
+    // Gives access to block 0: backdoor for Gen1a, key A authentication of sector 0 for Gen2
+    fn magic_unlock<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        generation: MagicGeneration,
+        uid: &Uid,
+        key: &Key,
+    ) -> Result<(), RFIDError> {
+        match generation {
+            MagicGeneration::Gen1a => self.magic_open_backdoor(serial),
+            MagicGeneration::Gen2 => self.mifare_authenticate(serial, KeyType::A, 0, key, uid),
+        }
+    }
+}
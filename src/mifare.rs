@@ -0,0 +1,71 @@
+// src/mifare.rs
+// MIFARE Classic keys and memory layout
+
+use crate::card_types::CardType;
+use crate::registers::{PICC_CMD_MF_AUTH_KEY_A, PICC_CMD_MF_AUTH_KEY_B};
+
+pub const MF_KEY_SIZE: usize = 6;
+pub const MF_BLOCK_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Key(pub [u8; MF_KEY_SIZE]);
+
+impl Key {
+    // Factory default transport key
+    pub const DEFAULT: Key = Key([0xFF; MF_KEY_SIZE]);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyType {
+    A,
+    B,
+}
+
+impl KeyType {
+    pub fn auth_command(self) -> u8 {
+        match self {
+            KeyType::A => PICC_CMD_MF_AUTH_KEY_A,
+            KeyType::B => PICC_CMD_MF_AUTH_KEY_B,
+        }
+    }
+}
+
+// Number of sectors for a MIFARE Classic card type, 0 for anything else
+pub fn sector_count(card_type: &CardType) -> u8 {
+    match card_type {
+        CardType::MifareMini => 5,
+        CardType::Mifare1K => 16,
+        CardType::Mifare4K => 40,
+        _ => 0,
+    }
+}
+
+// Sectors 0-31 have 4 blocks, the 8 extra sectors of a 4K card have 16 blocks
+pub fn sector_block_count(sector: u8) -> u8 {
+    if sector < 32 {
+        4
+    } else {
+        16
+    }
+}
+
+pub fn sector_first_block(sector: u8) -> u8 {
+    if sector < 32 {
+        sector * 4
+    } else {
+        128 + (sector - 32) * 16
+    }
+}
+
+// The last block of every sector holds the keys and access bits
+pub fn sector_trailer(sector: u8) -> u8 {
+    sector_first_block(sector) + sector_block_count(sector) - 1
+}
+
+pub fn block_sector(block: u8) -> u8 {
+    if block < 128 {
+        block / 4
+    } else {
+        32 + (block - 128) / 16
+    }
+}
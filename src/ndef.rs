@@ -0,0 +1,701 @@
+// src/ndef.rs
+// NFC Data Exchange Format messages, parsed and built over caller provided buffers.
+// Parsed records borrow from the message bytes, nothing is copied or allocated.
+// Chunked records are reported with `chunked` set but not reassembled.
+
+use core::fmt;
+use ufmt::{uDisplay, uWrite};
+
+// Record header flags
+const FLAG_MB: u8 = 0x80; // Message Begin
+const FLAG_ME: u8 = 0x40; // Message End
+const FLAG_CF: u8 = 0x20; // Chunk Flag
+const FLAG_SR: u8 = 0x10; // Short Record, 1 byte payload length
+const FLAG_IL: u8 = 0x08; // ID Length present
+const TNF_MASK: u8 = 0x07;
+
+// Well-known record types (NFC Forum RTD)
+pub const RTD_URI: &[u8] = b"U";
+pub const RTD_TEXT: &[u8] = b"T";
+pub const RTD_SMART_POSTER: &[u8] = b"Sp";
+const RTD_SP_ACTION: &[u8] = b"act";
+const RTD_SP_SIZE: &[u8] = b"s";
+const RTD_SP_TYPE: &[u8] = b"t";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NdefError {
+    Truncated,       // Message ends inside a record
+    InvalidRecord,   // Header or payload not valid for the record type
+    BufferTooSmall,  // Output buffer cannot hold the encoded data
+}
+
+// Type Name Format
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Tnf {
+    Empty,
+    WellKnown,
+    Media,       // RFC 2046 MIME type
+    AbsoluteUri,
+    External,    // NFC Forum external type, "domain:type"
+    Unknown,
+    Unchanged,   // Middle and last chunks of a chunked record
+    Reserved,
+}
+
+impl Tnf {
+    pub fn from_bits(bits: u8) -> Tnf {
+        match bits & TNF_MASK {
+            0 => Tnf::Empty,
+            1 => Tnf::WellKnown,
+            2 => Tnf::Media,
+            3 => Tnf::AbsoluteUri,
+            4 => Tnf::External,
+            5 => Tnf::Unknown,
+            6 => Tnf::Unchanged,
+            _ => Tnf::Reserved,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match self {
+            Tnf::Empty => 0,
+            Tnf::WellKnown => 1,
+            Tnf::Media => 2,
+            Tnf::AbsoluteUri => 3,
+            Tnf::External => 4,
+            Tnf::Unknown => 5,
+            Tnf::Unchanged => 6,
+            Tnf::Reserved => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Record<'a> {
+    pub tnf: Tnf,
+    pub record_type: &'a [u8],
+    pub id: &'a [u8],
+    pub payload: &'a [u8],
+    // Header flags of a parsed record. MB and ME are ignored when writing, MessageWriter sets them.
+    pub message_begin: bool,
+    pub message_end: bool,
+    pub chunked: bool,
+}
+
+impl<'a> Record<'a> {
+    pub fn new(tnf: Tnf, record_type: &'a [u8], payload: &'a [u8]) -> Self {
+        Record {
+            tnf,
+            record_type,
+            id: &[],
+            payload,
+            message_begin: false,
+            message_end: false,
+            chunked: false,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Record::new(Tnf::Empty, &[], &[])
+    }
+
+    pub fn mime(mime_type: &'a str, payload: &'a [u8]) -> Self {
+        Record::new(Tnf::Media, mime_type.as_bytes(), payload)
+    }
+
+    // `external_type` is "domain:type", e.g. "example.com:ticket"
+    pub fn external(external_type: &'a str, payload: &'a [u8]) -> Self {
+        Record::new(Tnf::External, external_type.as_bytes(), payload)
+    }
+
+    pub fn with_id(mut self, id: &'a [u8]) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn is_well_known(&self, record_type: &[u8]) -> bool {
+        self.tnf == Tnf::WellKnown && self.record_type == record_type
+    }
+
+    // Decodes a Well-Known URI record
+    pub fn uri(&self) -> Option<Uri<'a>> {
+        if !self.is_well_known(RTD_URI) {
+            return None;
+        }
+        Uri::parse(self.payload).ok()
+    }
+
+    // Decodes a Well-Known Text record
+    pub fn text(&self) -> Option<Text<'a>> {
+        if !self.is_well_known(RTD_TEXT) {
+            return None;
+        }
+        Text::parse(self.payload).ok()
+    }
+
+    // Decodes a Smart Poster record
+    pub fn smart_poster(&self) -> Option<SmartPoster<'a>> {
+        if !self.is_well_known(RTD_SMART_POSTER) {
+            return None;
+        }
+        SmartPoster::parse(self.payload).ok()
+    }
+
+    // Size of the encoded record, None if it does not fit a usize
+    pub fn encoded_len(&self) -> Option<usize> {
+        encoded_len(self.record_type.len(), self.id.len(), self.payload.len())
+    }
+}
+
+fn encoded_len(type_len: usize, id_len: usize, payload_len: usize) -> Option<usize> {
+    let length_field = if payload_len <= 0xFF { 1 } else { 4 };
+    let id_field = if id_len > 0 { 1 } else { 0 };
+    (2 + length_field + id_field + type_len)
+        .checked_add(id_len)?
+        .checked_add(payload_len)
+}
+
+// Iterates over the records of an NDEF message
+pub fn records(message: &[u8]) -> Records<'_> {
+    Records { data: message, offset: 0, done: message.is_empty() }
+}
+
+pub struct Records<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Records<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NdefError> {
+        let end = self.offset.checked_add(len).ok_or(NdefError::Truncated)?;
+        let bytes = self.data.get(self.offset..end).ok_or(NdefError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn parse_record(&mut self) -> Result<Record<'a>, NdefError> {
+        let header = self.take(1)?[0];
+        let type_len = self.take(1)?[0] as usize;
+        let payload_len = if header & FLAG_SR != 0 {
+            self.take(1)?[0] as usize
+        } else {
+            let len = self.take(4)?;
+            usize::try_from(u32::from_be_bytes([len[0], len[1], len[2], len[3]])).map_err(|_| NdefError::Truncated)?
+        };
+        let id_len = if header & FLAG_IL != 0 { self.take(1)?[0] as usize } else { 0 };
+
+        let tnf = Tnf::from_bits(header);
+        // Empty records have no type, id or payload, Unknown and Unchanged have no type
+        match tnf {
+            Tnf::Empty if type_len != 0 || id_len != 0 || payload_len != 0 => return Err(NdefError::InvalidRecord),
+            Tnf::Unknown | Tnf::Unchanged if type_len != 0 => return Err(NdefError::InvalidRecord),
+            Tnf::Reserved => return Err(NdefError::InvalidRecord),
+            _ => {}
+        }
+
+        Ok(Record {
+            tnf,
+            record_type: self.take(type_len)?,
+            id: self.take(id_len)?,
+            payload: self.take(payload_len)?,
+            message_begin: header & FLAG_MB != 0,
+            message_end: header & FLAG_ME != 0,
+            chunked: header & FLAG_CF != 0,
+        })
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, NdefError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let first = self.offset == 0;
+        let record = self.parse_record();
+        match record {
+            Ok(record) if record.message_begin != first => {
+                self.done = true;
+                Some(Err(NdefError::InvalidRecord))
+            }
+            Ok(record) => {
+                self.done = record.message_end;
+                Some(Ok(record))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// Builds an NDEF message into a caller provided buffer. MB is set on the first record and
+// ME on whichever record was pushed last.
+pub struct MessageWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    last_header: Option<usize>,
+}
+
+impl<'a> MessageWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        MessageWriter { buffer, len: 0, last_header: None }
+    }
+
+    pub fn push(&mut self, record: &Record) -> Result<(), NdefError> {
+        let flags = if record.chunked { FLAG_CF } else { 0 };
+        self.push_with(flags | record.tnf.bits(), record.record_type, record.id, record.payload.len(), |out| {
+            out.copy_from_slice(record.payload);
+            Ok(())
+        })
+    }
+
+    pub fn push_uri(&mut self, uri: &Uri) -> Result<(), NdefError> {
+        self.push_with(Tnf::WellKnown.bits(), RTD_URI, &[], uri.payload_len(), |out| uri.write_payload(out))
+    }
+
+    pub fn push_text(&mut self, text: &Text) -> Result<(), NdefError> {
+        self.push_with(Tnf::WellKnown.bits(), RTD_TEXT, &[], text.payload_len(), |out| text.write_payload(out))
+    }
+
+    pub fn push_smart_poster(&mut self, poster: &SmartPoster) -> Result<(), NdefError> {
+        let payload_len = poster.payload_len().ok_or(NdefError::BufferTooSmall)?;
+        self.push_with(Tnf::WellKnown.bits(), RTD_SMART_POSTER, &[], payload_len, |out| {
+            poster.write_payload(out)
+        })
+    }
+
+    // Encoded message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Writes the record header and lets `fill` write exactly `payload_len` payload bytes in place
+    fn push_with<F>(
+        &mut self,
+        tnf_and_flags: u8,
+        record_type: &[u8],
+        id: &[u8],
+        payload_len: usize,
+        fill: F,
+    ) -> Result<(), NdefError>
+    where
+        F: FnOnce(&mut [u8]) -> Result<(), NdefError>,
+    {
+        if record_type.len() > 0xFF || id.len() > 0xFF || payload_len > u32::MAX as usize {
+            return Err(NdefError::InvalidRecord);
+        }
+        let start = self.len;
+        let end = encoded_len(record_type.len(), id.len(), payload_len)
+            .and_then(|total| start.checked_add(total))
+            .ok_or(NdefError::BufferTooSmall)?;
+        let out = self.buffer.get_mut(start..end).ok_or(NdefError::BufferTooSmall)?;
+
+        let mut header = tnf_and_flags | FLAG_ME;
+        if self.last_header.is_none() {
+            header |= FLAG_MB;
+        }
+        let mut pos = 2;
+        out[1] = record_type.len() as u8;
+        if payload_len <= 0xFF {
+            header |= FLAG_SR;
+            out[pos] = payload_len as u8;
+            pos += 1;
+        } else {
+            out[pos..pos + 4].copy_from_slice(&(payload_len as u32).to_be_bytes());
+            pos += 4;
+        }
+        if !id.is_empty() {
+            header |= FLAG_IL;
+            out[pos] = id.len() as u8;
+            pos += 1;
+        }
+        out[0] = header;
+        out[pos..pos + record_type.len()].copy_from_slice(record_type);
+        pos += record_type.len();
+        out[pos..pos + id.len()].copy_from_slice(id);
+        pos += id.len();
+        fill(&mut out[pos..])?;
+
+        if let Some(previous) = self.last_header {
+            self.buffer[previous] &= !FLAG_ME;
+        }
+        self.last_header = Some(start);
+        self.len = end;
+        Ok(())
+    }
+}
+
+// URI identifier codes (NFC Forum URI RTD), the index is the code
+const URI_PREFIXES: [&str; 36] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
+
+// Well-Known URI record: an abbreviation code followed by the rest of the URI
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Uri<'a> {
+    pub prefix_code: u8,
+    pub rest: &'a str,
+}
+
+impl<'a> Uri<'a> {
+    // Abbreviates `uri` with the longest matching prefix
+    pub fn new(uri: &'a str) -> Self {
+        let mut best = 0;
+        for (code, prefix) in URI_PREFIXES.iter().enumerate().skip(1) {
+            if uri.starts_with(prefix) && prefix.len() > URI_PREFIXES[best].len() {
+                best = code;
+            }
+        }
+        Uri { prefix_code: best as u8, rest: &uri[URI_PREFIXES[best].len()..] }
+    }
+
+    pub fn parse(payload: &'a [u8]) -> Result<Self, NdefError> {
+        let (&code, rest) = payload.split_first().ok_or(NdefError::InvalidRecord)?;
+        if code as usize >= URI_PREFIXES.len() {
+            return Err(NdefError::InvalidRecord);
+        }
+        let rest = core::str::from_utf8(rest).map_err(|_| NdefError::InvalidRecord)?;
+        Ok(Uri { prefix_code: code, rest })
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        URI_PREFIXES.get(self.prefix_code as usize).copied().unwrap_or("")
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefix().len() + self.rest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Copies the full URI into `out`, returns its length
+    pub fn write_to(&self, out: &mut [u8]) -> Result<usize, NdefError> {
+        let prefix = self.prefix().as_bytes();
+        let len = self.len();
+        let out = out.get_mut(..len).ok_or(NdefError::BufferTooSmall)?;
+        out[..prefix.len()].copy_from_slice(prefix);
+        out[prefix.len()..].copy_from_slice(self.rest.as_bytes());
+        Ok(len)
+    }
+
+    pub fn payload_len(&self) -> usize {
+        1 + self.rest.len()
+    }
+
+    pub fn write_payload(&self, out: &mut [u8]) -> Result<(), NdefError> {
+        let out = out.get_mut(..self.payload_len()).ok_or(NdefError::BufferTooSmall)?;
+        out[0] = self.prefix_code;
+        out[1..].copy_from_slice(self.rest.as_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Display for Uri<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.prefix())?;
+        f.write_str(self.rest)
+    }
+}
+
+impl uDisplay for Uri<'_> {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        f.write_str(self.prefix())?;
+        f.write_str(self.rest)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16, // Big endian unless the text starts with a byte order mark
+}
+
+// Well-Known Text record: status byte, IANA language code, text. `text` holds the encoded
+// bytes; for UTF-16 use encode_utf16 to build them and chars() to decode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Text<'a> {
+    pub language: &'a str,
+    pub encoding: TextEncoding,
+    pub text: &'a [u8],
+}
+
+impl<'a> Text<'a> {
+    pub fn new(language: &'a str, text: &'a str) -> Self {
+        Text { language, encoding: TextEncoding::Utf8, text: text.as_bytes() }
+    }
+
+    pub fn parse(payload: &'a [u8]) -> Result<Self, NdefError> {
+        let (&status, rest) = payload.split_first().ok_or(NdefError::InvalidRecord)?;
+        let language_len = (status & 0x3F) as usize;
+        if rest.len() < language_len {
+            return Err(NdefError::InvalidRecord);
+        }
+        let (language, text) = rest.split_at(language_len);
+        let encoding = if status & 0x80 != 0 { TextEncoding::Utf16 } else { TextEncoding::Utf8 };
+        if encoding == TextEncoding::Utf16 && text.len() % 2 != 0 {
+            return Err(NdefError::InvalidRecord);
+        }
+        Ok(Text {
+            language: core::str::from_utf8(language).map_err(|_| NdefError::InvalidRecord)?,
+            encoding,
+            text,
+        })
+    }
+
+    // The text of a UTF-8 record
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self.encoding {
+            TextEncoding::Utf8 => core::str::from_utf8(self.text).ok(),
+            TextEncoding::Utf16 => None,
+        }
+    }
+
+    // Decoded characters for either encoding, each invalid sequence becomes U+FFFD
+    pub fn chars(&self) -> impl Iterator<Item = char> + 'a {
+        let (utf8, utf16) = match self.encoding {
+            TextEncoding::Utf8 => (self.text, &[][..]),
+            TextEncoding::Utf16 => (&[][..], self.text),
+        };
+        let little_endian = utf16.starts_with(&[0xFF, 0xFE]);
+        let utf16 = if utf16.starts_with(&[0xFF, 0xFE]) || utf16.starts_with(&[0xFE, 0xFF]) {
+            &utf16[2..]
+        } else {
+            utf16
+        };
+        let units = utf16.chunks_exact(2).map(move |unit| {
+            if little_endian {
+                u16::from_le_bytes([unit[0], unit[1]])
+            } else {
+                u16::from_be_bytes([unit[0], unit[1]])
+            }
+        });
+        Utf8Chars::new(utf8)
+            .chain(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)))
+    }
+
+    pub fn payload_len(&self) -> usize {
+        1 + self.language.len() + self.text.len()
+    }
+
+    pub fn write_payload(&self, out: &mut [u8]) -> Result<(), NdefError> {
+        if self.language.len() > 0x3F {
+            return Err(NdefError::InvalidRecord);
+        }
+        let out = out.get_mut(..self.payload_len()).ok_or(NdefError::BufferTooSmall)?;
+        let utf16 = match self.encoding {
+            TextEncoding::Utf8 => 0x00,
+            TextEncoding::Utf16 => 0x80,
+        };
+        out[0] = utf16 | self.language.len() as u8;
+        let (language, text) = out[1..].split_at_mut(self.language.len());
+        language.copy_from_slice(self.language.as_bytes());
+        text.copy_from_slice(self.text);
+        Ok(())
+    }
+}
+
+// Encodes `text` as UTF-16BE without a byte order mark, returns the number of bytes
+pub fn encode_utf16(text: &str, out: &mut [u8]) -> Result<usize, NdefError> {
+    let mut len = 0;
+    for unit in text.encode_utf16() {
+        let bytes = out.get_mut(len..len + 2).ok_or(NdefError::BufferTooSmall)?;
+        bytes.copy_from_slice(&unit.to_be_bytes());
+        len += 2;
+    }
+    Ok(len)
+}
+
+// Lossy UTF-8 decoding without allocation, like String::from_utf8_lossy: each invalid
+// sequence becomes one U+FFFD and decoding resumes after it
+struct Utf8Chars<'a> {
+    valid: core::str::Chars<'a>,
+    replacement: bool,
+    rest: &'a [u8],
+}
+
+impl<'a> Utf8Chars<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Utf8Chars { valid: "".chars(), replacement: false, rest: bytes }
+    }
+}
+
+impl Iterator for Utf8Chars<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if let Some(c) = self.valid.next() {
+                return Some(c);
+            }
+            if self.replacement {
+                self.replacement = false;
+                return Some(char::REPLACEMENT_CHARACTER);
+            }
+            if self.rest.is_empty() {
+                return None;
+            }
+            let (valid, skip) = match core::str::from_utf8(self.rest) {
+                Ok(valid) => (valid, self.rest.len()),
+                Err(err) => {
+                    let valid_up_to = err.valid_up_to();
+                    // A truncated sequence at the end has no error_len
+                    let invalid = err.error_len().unwrap_or(self.rest.len() - valid_up_to);
+                    self.replacement = true;
+                    (core::str::from_utf8(&self.rest[..valid_up_to]).unwrap_or(""), valid_up_to + invalid)
+                }
+            };
+            self.valid = valid.chars();
+            self.rest = &self.rest[skip..];
+        }
+    }
+}
+
+// Recommended action of a Smart Poster
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Execute, // Open the URI, dial the number, send the SMS
+    Save,
+    Edit,
+}
+
+// Smart Poster: a URI with an optional title, action, size and MIME type of the target.
+// Only the first title is decoded, iterate `records(payload)` for titles in other languages.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SmartPoster<'a> {
+    pub uri: Uri<'a>,
+    pub title: Option<Text<'a>>,
+    pub action: Option<Action>,
+    pub size: Option<u32>,
+    pub mime_type: Option<&'a str>,
+}
+
+impl<'a> SmartPoster<'a> {
+    pub fn new(uri: Uri<'a>) -> Self {
+        SmartPoster { uri, title: None, action: None, size: None, mime_type: None }
+    }
+
+    pub fn parse(payload: &'a [u8]) -> Result<Self, NdefError> {
+        let mut uri = None;
+        let mut poster = SmartPoster::new(Uri { prefix_code: 0, rest: "" });
+        for record in records(payload) {
+            let record = record?;
+            if record.tnf != Tnf::WellKnown {
+                continue;
+            }
+            match record.record_type {
+                RTD_URI => uri = Some(Uri::parse(record.payload)?),
+                RTD_TEXT if poster.title.is_none() => poster.title = Some(Text::parse(record.payload)?),
+                RTD_SP_ACTION => {
+                    poster.action = match record.payload.first() {
+                        Some(0) => Some(Action::Execute),
+                        Some(1) => Some(Action::Save),
+                        Some(2) => Some(Action::Edit),
+                        _ => None,
+                    }
+                }
+                RTD_SP_SIZE => {
+                    let size: [u8; 4] = record.payload.try_into().map_err(|_| NdefError::InvalidRecord)?;
+                    poster.size = Some(u32::from_be_bytes(size));
+                }
+                RTD_SP_TYPE => {
+                    poster.mime_type =
+                        Some(core::str::from_utf8(record.payload).map_err(|_| NdefError::InvalidRecord)?);
+                }
+                _ => {}
+            }
+        }
+        // The URI record is mandatory
+        poster.uri = uri.ok_or(NdefError::InvalidRecord)?;
+        Ok(poster)
+    }
+
+    // Size of the nested message, None if it does not fit a usize (no buffer could hold it)
+    pub fn payload_len(&self) -> Option<usize> {
+        let records = [
+            encoded_len(RTD_URI.len(), 0, self.uri.payload_len()),
+            self.title.as_ref().map_or(Some(0), |title| encoded_len(RTD_TEXT.len(), 0, title.payload_len())),
+            self.action.map_or(Some(0), |_| encoded_len(RTD_SP_ACTION.len(), 0, 1)),
+            self.size.map_or(Some(0), |_| encoded_len(RTD_SP_SIZE.len(), 0, 4)),
+            self.mime_type.map_or(Some(0), |mime_type| encoded_len(RTD_SP_TYPE.len(), 0, mime_type.len())),
+        ];
+        records
+            .iter()
+            .try_fold(0usize, |len, record| len.checked_add((*record)?))
+    }
+
+    // Writes the nested NDEF message
+    pub fn write_payload(&self, out: &mut [u8]) -> Result<(), NdefError> {
+        let mut writer = MessageWriter::new(out);
+        writer.push_uri(&self.uri)?;
+        if let Some(title) = &self.title {
+            writer.push_text(title)?;
+        }
+        if let Some(action) = self.action {
+            let action = match action {
+                Action::Execute => [0u8],
+                Action::Save => [1u8],
+                Action::Edit => [2u8],
+            };
+            writer.push(&Record::new(Tnf::WellKnown, RTD_SP_ACTION, &action))?;
+        }
+        if let Some(size) = self.size {
+            writer.push(&Record::new(Tnf::WellKnown, RTD_SP_SIZE, &size.to_be_bytes()))?;
+        }
+        if let Some(mime_type) = self.mime_type {
+            writer.push(&Record::new(Tnf::WellKnown, RTD_SP_TYPE, mime_type.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
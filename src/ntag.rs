@@ -0,0 +1,325 @@
+// src/ntag.rs
+// NTAG21x / MIFARE Ultralight EV1 specific commands
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::{CardType, Uid};
+use crate::errors::RFIDError;
+use crate::registers::{
+    PICC_CMD_CHECK_TEARING, PICC_CMD_GET_VERSION, PICC_CMD_INCR_CNT, PICC_CMD_PWD_AUTH, PICC_CMD_READ_CNT,
+    PICC_CMD_READ_SIG, PICC_CMD_UL_C_AUTH, PICC_CMD_UL_C_AUTH_CONTINUE,
+};
+use crate::rfid_rc522::RfidRc522;
+use crate::ultralight::UL_PAGE_SIZE;
+
+// GET_VERSION answer
+#[derive(Clone, Copy, PartialEq)]
+pub struct VersionInfo {
+    pub vendor_id: u8,       // 0x04 for NXP
+    pub product_type: u8,    // 0x03 MIFARE Ultralight, 0x04 NTAG
+    pub product_subtype: u8, // Input capacitance / interface variant
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub storage_size: u8,    // Encoded, see storage_bytes
+    pub protocol_type: u8,   // 0x03 for ISO/IEC 14443-3
+}
+
+impl VersionInfo {
+    pub fn parse(data: &[u8; 8]) -> Self {
+        // data[0] is a fixed header
+        VersionInfo {
+            vendor_id: data[1],
+            product_type: data[2],
+            product_subtype: data[3],
+            major_version: data[4],
+            minor_version: data[5],
+            storage_size: data[6],
+            protocol_type: data[7],
+        }
+    }
+
+    // User memory size in bytes. The 7 MSBs encode n for 2^n bytes; if the LSB is set
+    // the size is between 2^n and 2^(n+1) and the lower bound is returned.
+    pub fn storage_bytes(&self) -> u16 {
+        1u16 << (self.storage_size >> 1).min(15)
+    }
+
+    pub fn card_type(&self) -> CardType {
+        if self.vendor_id != 0x04 {
+            return CardType::Unknown;
+        }
+        match (self.product_type, self.product_subtype, self.major_version, self.minor_version, self.storage_size) {
+            (0x03, _, 0x01, _, 0x0B) => CardType::MifareUltralightEv1Mf0ul11,
+            (0x03, _, 0x01, _, 0x0E) => CardType::MifareUltralightEv1Mf0ul21,
+            (0x04, 0x01, 0x01, _, 0x0B) => CardType::Ntag210,
+            (0x04, 0x01, 0x01, _, 0x0E) => CardType::Ntag212,
+            (0x04, 0x02, 0x01, _, 0x0F) => CardType::Ntag213,
+            (0x04, 0x02, 0x01, _, 0x11) => CardType::Ntag215,
+            (0x04, 0x02, 0x01, _, 0x13) => CardType::Ntag216,
+            (0x04, 0x05, 0x02, 0x01, 0x13) => CardType::NtagI2c1K,
+            (0x04, 0x05, 0x02, 0x01, 0x15) => CardType::NtagI2c2K,
+            (0x04, 0x05, 0x02, 0x02, 0x13) => CardType::NtagI2cPlus1K,
+            (0x04, 0x05, 0x02, 0x02, 0x15) => CardType::NtagI2cPlus2K,
+            _ => CardType::Unknown,
+        }
+    }
+}
+
+// READ_CNT address of the NTAG21x NFC counter. Ultralight EV1 has counters 0 to 2.
+pub const NTAG_NFC_COUNTER: u8 = 0x02;
+
+// CHECK_TEARING_EVENT answer when the last counter write completed
+const TEARING_FLAG_VALID: u8 = 0xBD;
+
+// Page address of CFG0 for tags with password protection; CFG1, PWD and PACK follow it
+pub fn config_page(card_type: &CardType) -> Option<u8> {
+    match card_type {
+        CardType::MifareUltralightEv1Mf0ul11 | CardType::Ntag210 => Some(0x10),
+        CardType::MifareUltralightEv1Mf0ul21 | CardType::Ntag212 => Some(0x25),
+        CardType::Ntag213 => Some(0x29),
+        CardType::Ntag215 => Some(0x83),
+        CardType::Ntag216 => Some(0xE3),
+        _ => None,
+    }
+}
+
+// What the tag mirrors as ASCII into its NDEF data (NTAG21x only)
+#[derive(Clone, Copy, PartialEq)]
+pub enum MirrorConf {
+    None,
+    Uid,
+    NfcCounter,
+    UidAndNfcCounter,
+}
+
+// Contents of the CFG0 and CFG1 configuration pages
+#[derive(Clone, Copy, PartialEq)]
+pub struct NtagConfig {
+    pub mirror_conf: MirrorConf,
+    pub mirror_byte: u8,       // Byte in mirror_page where the mirror starts (0-3)
+    pub strg_mod_en: bool,     // Strong modulation
+    pub mirror_page: u8,
+    pub auth0: u8,             // First page protected by the password, above the last page disables protection
+    pub prot: bool,            // false: only writes are protected, true: reads and writes
+    pub cfglck: bool,          // Permanently locks the configuration pages
+    pub nfc_cnt_en: bool,      // NFC counter incremented on the first read or fast read
+    pub nfc_cnt_pwd_prot: bool, // READ_CNT needs password authentication
+    pub authlim: u8,           // Allowed failed password attempts (2^authlim), 0 disables the limit
+}
+
+impl NtagConfig {
+    pub fn parse(cfg0: &[u8; UL_PAGE_SIZE], cfg1: &[u8; UL_PAGE_SIZE]) -> Self {
+        NtagConfig {
+            mirror_conf: match cfg0[0] >> 6 {
+                0 => MirrorConf::None,
+                1 => MirrorConf::Uid,
+                2 => MirrorConf::NfcCounter,
+                _ => MirrorConf::UidAndNfcCounter,
+            },
+            mirror_byte: (cfg0[0] >> 4) & 0x03,
+            strg_mod_en: cfg0[0] & 0x04 != 0,
+            mirror_page: cfg0[2],
+            auth0: cfg0[3],
+            prot: cfg1[0] & 0x80 != 0,
+            cfglck: cfg1[0] & 0x40 != 0,
+            nfc_cnt_en: cfg1[0] & 0x10 != 0,
+            nfc_cnt_pwd_prot: cfg1[0] & 0x08 != 0,
+            authlim: cfg1[0] & 0x07,
+        }
+    }
+
+    // Updates the configuration bytes in CFG0 and CFG1, leaving RFUI bytes untouched
+    pub fn apply(&self, cfg0: &mut [u8; UL_PAGE_SIZE], cfg1: &mut [u8; UL_PAGE_SIZE]) {
+        let mirror_conf = match self.mirror_conf {
+            MirrorConf::None => 0,
+            MirrorConf::Uid => 1,
+            MirrorConf::NfcCounter => 2,
+            MirrorConf::UidAndNfcCounter => 3,
+        };
+        cfg0[0] = (cfg0[0] & 0x0B) | (mirror_conf << 6) | ((self.mirror_byte & 0x03) << 4) | ((self.strg_mod_en as u8) << 2);
+        cfg0[2] = self.mirror_page;
+        cfg0[3] = self.auth0;
+        cfg1[0] = (cfg1[0] & 0x20)
+            | ((self.prot as u8) << 7)
+            | ((self.cfglck as u8) << 6)
+            | ((self.nfc_cnt_en as u8) << 4)
+            | ((self.nfc_cnt_pwd_prot as u8) << 3)
+            | (self.authlim & 0x07);
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // GET_VERSION (0x60). Original Ultralight and Ultralight C tags don't support it and
+    // answer with a NAK or not at all, which leaves them in the IDLE state.
+    pub fn get_version<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<VersionInfo, RFIDError> {
+        let mut command = [PICC_CMD_GET_VERSION, 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &command[..1], &mut crc)?;
+        command[1] = crc[0];
+        command[2] = crc[1];
+
+        // 8 bytes + CRC_A
+        let mut response = [0u8; 10];
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &command, &mut response, &mut valid_bits, 0, true)?;
+        if received != response.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+
+        let mut data = [0u8; 8];
+        data.copy_from_slice(&response[..8]);
+        Ok(VersionInfo::parse(&data))
+    }
+
+    // Precise type of a selected Type 2 Tag (SAK 0x00). Tags without GET_VERSION are probed
+    // with the first Ultralight C authentication step, then selected again, so the tag is
+    // ACTIVE in every case.
+    pub fn identify_type2<W: ufmt::uWrite>(&mut self, serial: &mut W, uid: &Uid) -> Result<CardType, RFIDError> {
+        match self.get_version(serial) {
+            Ok(version) => Ok(version.card_type()),
+            Err(RFIDError::Nak(_)) | Err(RFIDError::Timeout) => {
+                self.reselect(serial, uid)?;
+                let mut response = [0u8; 11];
+                let ultralight_c = self
+                    .ultralight_c_exchange(serial, &[PICC_CMD_UL_C_AUTH, 0x00], &mut response)
+                    .is_ok_and(|_| response[0] == PICC_CMD_UL_C_AUTH_CONTINUE);
+                // Leave the half done authentication
+                self.halt_a(serial).ok();
+                self.reselect(serial, uid)?;
+                Ok(if ultralight_c { CardType::MifareUltralightC } else { CardType::MifareUltralight })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // PWD_AUTH (0x1B). Returns the 2 byte PACK the tag answers with, compare it to the
+    // expected value to make sure the tag is genuine.
+    pub fn pwd_auth<W: ufmt::uWrite>(&mut self, serial: &mut W, pwd: &[u8; 4]) -> Result<[u8; 2], RFIDError> {
+        let mut command = [PICC_CMD_PWD_AUTH, pwd[0], pwd[1], pwd[2], pwd[3], 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &command[..5], &mut crc)?;
+        command[5] = crc[0];
+        command[6] = crc[1];
+
+        // PACK + CRC_A
+        let mut response = [0u8; 4];
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &command, &mut response, &mut valid_bits, 0, true)?;
+        if received != response.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+        Ok([response[0], response[1]])
+    }
+
+    // Reads CFG0 and CFG1. When PROT is set the tag must be authenticated with pwd_auth first.
+    pub fn ntag_read_config<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+    ) -> Result<NtagConfig, RFIDError> {
+        let (cfg0, cfg1) = self.ntag_read_config_pages(serial, card_type)?;
+        Ok(NtagConfig::parse(&cfg0, &cfg1))
+    }
+
+    // Writes CFG0 and then CFG1, so a new AUTH0 is in place before CFGLCK takes effect
+    pub fn ntag_write_config<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+        config: &NtagConfig,
+    ) -> Result<(), RFIDError> {
+        let page = config_page(card_type).ok_or(RFIDError::InvalidResponse)?;
+        let (mut cfg0, mut cfg1) = self.ntag_read_config_pages(serial, card_type)?;
+        config.apply(&mut cfg0, &mut cfg1);
+        self.ultralight_write(serial, page, &cfg0)?;
+        self.ultralight_write(serial, page + 1, &cfg1)
+    }
+
+    // Writes the PWD and PACK pages. They always read back as zeros.
+    pub fn ntag_set_password<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+        pwd: &[u8; 4],
+        pack: &[u8; 2],
+    ) -> Result<(), RFIDError> {
+        let page = config_page(card_type).ok_or(RFIDError::InvalidResponse)?;
+        self.ultralight_write(serial, page + 2, pwd)?;
+        self.ultralight_write(serial, page + 3, &[pack[0], pack[1], 0, 0])
+    }
+
+    fn ntag_read_config_pages<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        card_type: &CardType,
+    ) -> Result<([u8; UL_PAGE_SIZE], [u8; UL_PAGE_SIZE]), RFIDError> {
+        let page = config_page(card_type).ok_or(RFIDError::InvalidResponse)?;
+        let data = self.ultralight_read(serial, page)?;
+        let mut cfg0 = [0u8; UL_PAGE_SIZE];
+        let mut cfg1 = [0u8; UL_PAGE_SIZE];
+        cfg0.copy_from_slice(&data[..4]);
+        cfg1.copy_from_slice(&data[4..8]);
+        Ok((cfg0, cfg1))
+    }
+
+    // READ_SIG (0x3C). Check the result with originality::verify_signature.
+    pub fn read_sig<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<[u8; 32], RFIDError> {
+        let mut response = [0u8; 34];
+        self.ntag_command(serial, PICC_CMD_READ_SIG, 0x00, &mut response)?;
+        let mut signature = [0u8; 32];
+        signature.copy_from_slice(&response[..32]);
+        Ok(signature)
+    }
+
+    // READ_CNT (0x39). Use NTAG_NFC_COUNTER on NTAG21x, the counter must be enabled with NFC_CNT_EN.
+    pub fn read_cnt<W: ufmt::uWrite>(&mut self, serial: &mut W, counter: u8) -> Result<u32, RFIDError> {
+        let mut response = [0u8; 5];
+        self.ntag_command(serial, PICC_CMD_READ_CNT, counter, &mut response)?;
+        // 24 bit, LSB first
+        Ok(u32::from_le_bytes([response[0], response[1], response[2], 0]))
+    }
+
+    // INCR_CNT (0xA5), Ultralight EV1 only. The counter does not wrap, the tag NAKs an overflow.
+    pub fn incr_cnt<W: ufmt::uWrite>(&mut self, serial: &mut W, counter: u8, increment: u32) -> Result<(), RFIDError> {
+        if increment > 0x00FF_FFFF {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let value = increment.to_le_bytes();
+        let command = [PICC_CMD_INCR_CNT, counter, value[0], value[1], value[2], 0x00];
+        self.mifare_transceive(serial, &command, false)
+    }
+
+    // CHECK_TEARING_EVENT (0x3E), Ultralight EV1 only. Returns true when the last
+    // increment of the counter was torn (interrupted before it completed).
+    pub fn check_tearing_event<W: ufmt::uWrite>(&mut self, serial: &mut W, counter: u8) -> Result<bool, RFIDError> {
+        let mut response = [0u8; 3];
+        self.ntag_command(serial, PICC_CMD_CHECK_TEARING, counter, &mut response)?;
+        Ok(response[0] != TEARING_FLAG_VALID)
+    }
+
+    // Sends a two byte command and expects exactly `response.len()` bytes back, CRC_A included
+    fn ntag_command<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        command: u8,
+        arg: u8,
+        response: &mut [u8],
+    ) -> Result<(), RFIDError> {
+        let mut frame = [command, arg, 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &frame[..2], &mut crc)?;
+        frame[2] = crc[0];
+        frame[3] = crc[1];
+
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &frame, response, &mut valid_bits, 0, true)?;
+        if received != response.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+        Ok(())
+    }
+}
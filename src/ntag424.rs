@@ -0,0 +1,399 @@
+// src/ntag424.rs
+// NTAG 424 DNA: file settings with Secure Dynamic Messaging (SDM) on the reader side, and
+// a host side verifier for the URLs the tag mirrors.
+// The tag speaks ISO wrapped DESFire EV2 commands: select the NDEF application with
+// type4_select_ndef_application, then use Desfire (Framing::IsoWrapped) to authenticate
+// with AuthMode::Ev2First and an AES key, and to change file settings.
+// On every read of the NDEF file the tag mirrors into the URL:
+//  - PICCData: UID and read counter, encrypted with the SDMMetaRead key, or in plain
+//  - optionally part of the file, encrypted with a session key of the SDMFileRead key
+//  - the SDM MAC over the file from SDMMACInputOffset up to the MAC, with a session key
+//    of the SDMFileRead key, so a copied URL only verifies for one counter value
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::desfire::{AccessRights, CommMode, Desfire};
+use crate::desfire_crypto::{truncate_mac, DesfireKey, Protection, DF_SECURE_OVERHEAD};
+use crate::errors::RFIDError;
+
+pub const NTAG424_CHANGE_FILE_SETTINGS: u8 = 0x5F;
+
+// Files of the NDEF application
+pub const NTAG424_CC_FILE: u8 = 0x01;
+pub const NTAG424_NDEF_FILE: u8 = 0x02;
+pub const NTAG424_PROPRIETARY_FILE: u8 = 0x03;
+
+// FileOption bit enabling SDM
+const FILE_OPTION_SDM: u8 = 0x40;
+
+// SDMOptions bits
+const SDM_UID_MIRROR: u8 = 0x80;
+const SDM_READ_CTR_MIRROR: u8 = 0x40;
+const SDM_READ_CTR_LIMIT: u8 = 0x20;
+const SDM_ENC_FILE_DATA: u8 = 0x10;
+const SDM_ASCII: u8 = 0x01;
+
+// PICCDataTag bits
+const PICC_DATA_UID: u8 = 0x80;
+const PICC_DATA_READ_CTR: u8 = 0x40;
+const PICC_DATA_UID_LENGTH: u8 = 0x0F;
+
+// Labels of the SDM session vectors
+const SV_ENC: [u8; 6] = [0xC3, 0x3C, 0x00, 0x01, 0x00, 0x80];
+const SV_MAC: [u8; 6] = [0x3C, 0xC3, 0x00, 0x01, 0x00, 0x80];
+
+// SDM access right values besides key numbers 0-4
+pub const SDM_PLAIN: u8 = 0x0E; // SDMMetaRead: mirror UID and counter in plain
+pub const SDM_NONE: u8 = 0x0F;  // No PICCData, no SDM MAC, no counter retrieval
+
+// Secure Dynamic Messaging configuration. Offsets are byte positions in the file where the
+// tag writes its ASCII hex mirrors; the fields that do not apply are not sent.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SdmConfig {
+    pub uid_mirror: bool,
+    pub read_ctr_mirror: bool,
+    pub read_ctr_limit: Option<u32>,
+    pub enc_file_data: bool,
+    pub meta_read: u8, // Key encrypting PICCData, SDM_PLAIN or SDM_NONE
+    pub file_read: u8, // Key of the SDM MAC and file encryption, or SDM_NONE
+    pub ctr_ret: u8,   // Key allowing GetFileCounters, or SDM_NONE
+    pub uid_offset: u32,        // Plain UID mirror
+    pub read_ctr_offset: u32,   // Plain counter mirror
+    pub picc_data_offset: u32,  // Encrypted PICCData, 32 characters
+    pub mac_input_offset: u32,
+    pub enc_offset: u32,        // Encrypted file data, a multiple of 32 characters
+    pub enc_length: u32,
+    pub mac_offset: u32,        // SDM MAC, 16 characters
+}
+
+impl SdmConfig {
+    fn options(&self) -> u8 {
+        let mut options = SDM_ASCII;
+        if self.uid_mirror {
+            options |= SDM_UID_MIRROR;
+        }
+        if self.read_ctr_mirror {
+            options |= SDM_READ_CTR_MIRROR;
+        }
+        if self.read_ctr_limit.is_some() {
+            options |= SDM_READ_CTR_LIMIT;
+        }
+        if self.enc_file_data {
+            options |= SDM_ENC_FILE_DATA;
+        }
+        options
+    }
+
+    // SDMOptions, SDMAccessRights and the offsets that apply, returns the length
+    fn encode(&self, out: &mut [u8; 32]) -> usize {
+        out[0] = self.options();
+        out[1] = 0xF0 | (self.ctr_ret & 0x0F);
+        out[2] = (self.meta_read & 0x0F) << 4 | (self.file_read & 0x0F);
+        let mut length = 3;
+        let mut push = |value: u32| {
+            out[length..length + 3].copy_from_slice(&value.to_le_bytes()[..3]);
+            length += 3;
+        };
+        if self.meta_read == SDM_PLAIN {
+            if self.uid_mirror {
+                push(self.uid_offset);
+            }
+            if self.read_ctr_mirror {
+                push(self.read_ctr_offset);
+            }
+        } else if self.meta_read != SDM_NONE {
+            push(self.picc_data_offset);
+        }
+        if self.file_read != SDM_NONE {
+            push(self.mac_input_offset);
+            if self.enc_file_data {
+                push(self.enc_offset);
+                push(self.enc_length);
+            }
+            push(self.mac_offset);
+        }
+        if let Some(limit) = self.read_ctr_limit {
+            push(limit);
+        }
+        length
+    }
+}
+
+impl<SPI, CS, W> Desfire<'_, SPI, CS, W>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+    W: ufmt::uWrite,
+{
+    // ChangeFileSettings, enciphered with the session of the file's Change key. `sdm` enables
+    // Secure Dynamic Messaging (NDEF and proprietary files), None disables it.
+    pub fn change_file_settings(
+        &mut self,
+        file_no: u8,
+        comm_mode: CommMode,
+        access_rights: AccessRights,
+        sdm: Option<&SdmConfig>,
+    ) -> Result<(), RFIDError> {
+        let mut data = [0u8; 35];
+        data[0] = comm_mode.bits() | if sdm.is_some() { FILE_OPTION_SDM } else { 0 };
+        data[1..3].copy_from_slice(&access_rights.bits().to_le_bytes());
+        let mut length = 3;
+        if let Some(sdm) = sdm {
+            let mut encoded = [0u8; 32];
+            let count = sdm.encode(&mut encoded);
+            data[3..3 + count].copy_from_slice(&encoded[..count]);
+            length += count;
+        }
+        self.command(
+            NTAG424_CHANGE_FILE_SETTINGS,
+            &[file_no],
+            &data[..length],
+            Protection::File(CommMode::Enciphered),
+            &mut [0u8; DF_SECURE_OVERHEAD],
+        )?;
+        Ok(())
+    }
+}
+
+// UID and read counter mirrored by the tag
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PiccData {
+    pub uid: Option<[u8; 7]>,
+    pub read_ctr: Option<u32>,
+}
+
+impl PiccData {
+    // Decrypts the 16 bytes of encrypted PICCData with the SDMMetaRead key
+    pub fn decrypt(meta_read_key: &[u8; 16], encrypted: &[u8; 16]) -> Result<Self, RFIDError> {
+        let mut plain = *encrypted;
+        DesfireKey::Aes(*meta_read_key).cipher().cbc_decrypt(&mut [0u8; 16], &mut plain);
+        let tag = plain[0];
+        let mut pos = 1;
+        let uid = if tag & PICC_DATA_UID != 0 {
+            if tag & PICC_DATA_UID_LENGTH != 7 {
+                return Err(RFIDError::IntegrityError);
+            }
+            let mut uid = [0u8; 7];
+            uid.copy_from_slice(&plain[1..8]);
+            pos += 7;
+            Some(uid)
+        } else {
+            None
+        };
+        let read_ctr = if tag & PICC_DATA_READ_CTR != 0 {
+            Some(u32::from_le_bytes([plain[pos], plain[pos + 1], plain[pos + 2], 0]))
+        } else {
+            None
+        };
+        Ok(PiccData { uid, read_ctr })
+    }
+
+    // SDM session keys (KSesSDMFileReadENC, KSesSDMFileReadMAC) of the SDMFileRead key
+    pub fn session_keys(&self, file_read_key: &[u8; 16]) -> ([u8; 16], [u8; 16]) {
+        // SV = label || UID || SDMReadCtr, zero padded to 16 bytes
+        let mut context = [0u8; 10];
+        let mut length = 0;
+        if let Some(uid) = self.uid {
+            context[..7].copy_from_slice(&uid);
+            length = 7;
+        }
+        if let Some(read_ctr) = self.read_ctr {
+            context[length..length + 3].copy_from_slice(&read_ctr.to_le_bytes()[..3]);
+            length += 3;
+        }
+        let padding = [0u8; 16];
+        let padding_len = (16 - (6 + length) % 16) % 16;
+        let cipher = DesfireKey::Aes(*file_read_key).cipher();
+        let enc = cipher.cmac(&[0u8; 16], &[&SV_ENC, &context[..length], &padding[..padding_len]]);
+        let mac = cipher.cmac(&[0u8; 16], &[&SV_MAC, &context[..length], &padding[..padding_len]]);
+        (enc, mac)
+    }
+
+    // SDM MAC over the file data from SDMMACInputOffset up to the MAC
+    pub fn sdm_mac(&self, file_read_key: &[u8; 16], mac_input: &[u8]) -> [u8; 8] {
+        let (_, mac_key) = self.session_keys(file_read_key);
+        truncate_mac(&DesfireKey::Aes(mac_key).cipher().cmac(&[0u8; 16], &[mac_input]))
+    }
+
+    // Decrypts mirrored file data in place, `data` is a multiple of 16 bytes
+    pub fn decrypt_file_data(&self, file_read_key: &[u8; 16], data: &mut [u8]) -> Result<(), RFIDError> {
+        let read_ctr = self.read_ctr.ok_or(RFIDError::InvalidResponse)?;
+        if data.len() % 16 != 0 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let (enc_key, _) = self.session_keys(file_read_key);
+        let cipher = DesfireKey::Aes(enc_key).cipher();
+        // IV = E(KSesSDMFileReadENC, SDMReadCtr || 0^13)
+        let mut iv = [0u8; 16];
+        iv[..3].copy_from_slice(&read_ctr.to_le_bytes()[..3]);
+        cipher.cbc_encrypt(&mut [0u8; 16], &mut iv);
+        cipher.cbc_decrypt(&mut iv, data);
+        Ok(())
+    }
+}
+
+// Names of the URL query parameters the tag mirrors into, as laid out in the NDEF file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SdmUrlParams<'a> {
+    pub picc_data: Option<&'a str>, // Encrypted PICCData
+    pub uid: Option<&'a str>,       // Plain UID mirror
+    pub read_ctr: Option<&'a str>,  // Plain counter mirror
+    pub enc_data: Option<&'a str>,  // Encrypted file data
+    pub mac: &'a str,
+    // Parameter whose value starts the MAC input (SDMMACInputOffset), None for an empty input
+    pub mac_input: Option<&'a str>,
+}
+
+// A verified SDM reading
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SdmReading {
+    pub picc: PiccData,
+    pub file_data_len: usize, // Decrypted bytes in the caller's buffer
+}
+
+// Host side check of a URL read from an NTAG 424 DNA: decrypts PICCData (or takes the plain
+// mirrors), validates the SDM MAC and decrypts the mirrored file data into `file_data`.
+// Callers should also reject counters they have already seen.
+pub fn verify_sdm_url(
+    url: &str,
+    params: &SdmUrlParams,
+    meta_read_key: &[u8; 16],
+    file_read_key: &[u8; 16],
+    file_data: &mut [u8],
+) -> Result<SdmReading, RFIDError> {
+    let picc = match params.picc_data {
+        Some(name) => {
+            let mut encrypted = [0u8; 16];
+            decode_hex(query_value(url, name)?.1, &mut encrypted)?;
+            PiccData::decrypt(meta_read_key, &encrypted)?
+        }
+        None => {
+            let uid = match params.uid {
+                Some(name) => {
+                    let mut uid = [0u8; 7];
+                    decode_hex(query_value(url, name)?.1, &mut uid)?;
+                    Some(uid)
+                }
+                None => None,
+            };
+            let read_ctr = match params.read_ctr {
+                Some(name) => {
+                    let mut ctr = [0u8; 3];
+                    decode_hex(query_value(url, name)?.1, &mut ctr)?;
+                    Some(u32::from_be_bytes([0, ctr[0], ctr[1], ctr[2]]))
+                }
+                None => None,
+            };
+            PiccData { uid, read_ctr }
+        }
+    };
+
+    let (mac_start, mac_hex) = query_value(url, params.mac)?;
+    let mut mac = [0u8; 8];
+    decode_hex(mac_hex, &mut mac)?;
+    let input_start = match params.mac_input {
+        Some(name) => query_value(url, name)?.0,
+        None => mac_start,
+    };
+    let input = url.as_bytes().get(input_start..mac_start).ok_or(RFIDError::InvalidResponse)?;
+    // Compared in constant time, the backend must not reveal how many bytes of a forged MAC match
+    let expected = picc.sdm_mac(file_read_key, input);
+    let difference = expected.iter().zip(mac.iter()).fold(0u8, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        return Err(RFIDError::IntegrityError);
+    }
+
+    let mut file_data_len = 0;
+    if let Some(name) = params.enc_data {
+        let hex = query_value(url, name)?.1;
+        file_data_len = hex.len() / 2;
+        let data = file_data.get_mut(..file_data_len).ok_or(RFIDError::NoRoom)?;
+        decode_hex(hex, data)?;
+        picc.decrypt_file_data(file_read_key, data)?;
+    }
+    Ok(SdmReading { picc, file_data_len })
+}
+
+// Position and text of the value of query parameter `name`
+fn query_value<'u>(url: &'u str, name: &str) -> Result<(usize, &'u str), RFIDError> {
+    let query_start = url.find('?').ok_or(RFIDError::InvalidResponse)? + 1;
+    let mut start = query_start;
+    for pair in url[query_start..].split('&') {
+        if let Some(value) = pair.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            let value_start = start + name.len() + 1;
+            return Ok((value_start, value));
+        }
+        start += pair.len() + 1;
+    }
+    Err(RFIDError::InvalidResponse)
+}
+
+// Decodes exactly `out.len()` bytes of hex digits, either case
+fn decode_hex(hex: &str, out: &mut [u8]) -> Result<(), RFIDError> {
+    if hex.len() != out.len() * 2 {
+        return Err(RFIDError::InvalidResponse);
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8).ok_or(RFIDError::InvalidResponse);
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AN12196 examples, SDMMetaRead and SDMFileRead keys all zero
+    const KEY: [u8; 16] = [0u8; 16];
+    const PICC_DATA_URL: &str = "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E88&c=94EED9EE65337086";
+    const FILE_DATA_URL: &str = "https://www.my424dna.com/?picc_data=FDE4AFA99B5C820A2C1BB0F1C792D0EB\
+                                 &enc=94592FDE69FA06E8E3B6CA686A22842B&cmac=C48B89C17A233B2C";
+
+    const PICC_DATA_PARAMS: SdmUrlParams = SdmUrlParams {
+        picc_data: Some("e"),
+        uid: None,
+        read_ctr: None,
+        enc_data: None,
+        mac: "c",
+        mac_input: None,
+    };
+    const FILE_DATA_PARAMS: SdmUrlParams = SdmUrlParams {
+        picc_data: Some("picc_data"),
+        uid: None,
+        read_ctr: None,
+        enc_data: Some("enc"),
+        mac: "cmac",
+        mac_input: Some("enc"),
+    };
+
+    #[test]
+    fn verifies_picc_data_url() {
+        let reading = verify_sdm_url(PICC_DATA_URL, &PICC_DATA_PARAMS, &KEY, &KEY, &mut []).unwrap();
+        assert_eq!(reading.picc.uid, Some([0x04, 0xDE, 0x5F, 0x1E, 0xAC, 0xC0, 0x40]));
+        assert_eq!(reading.picc.read_ctr, Some(0x3D));
+        assert_eq!(reading.file_data_len, 0);
+    }
+
+    #[test]
+    fn verifies_and_decrypts_file_data_url() {
+        let mut file_data = [0u8; 16];
+        let reading = verify_sdm_url(FILE_DATA_URL, &FILE_DATA_PARAMS, &KEY, &KEY, &mut file_data).unwrap();
+        assert_eq!(reading.picc.uid, Some([0x04, 0x95, 0x8C, 0xAA, 0x5C, 0x5E, 0x80]));
+        assert_eq!(reading.picc.read_ctr, Some(1));
+        assert_eq!(reading.file_data_len, 16);
+        assert_eq!(&file_data, b"xxxxxxxxxxxxxxxx");
+    }
+
+    #[test]
+    fn rejects_forged_mac() {
+        let forged = "https://choose.url.com/ntag424?e=EF963FF7828658A599F3041510671E88&c=94EED9EE65337087";
+        let result = verify_sdm_url(forged, &PICC_DATA_PARAMS, &KEY, &KEY, &mut []);
+        assert!(result == Err(RFIDError::IntegrityError));
+
+        let mut file_read_key = KEY;
+        file_read_key[0] = 0x01;
+        let result = verify_sdm_url(PICC_DATA_URL, &PICC_DATA_PARAMS, &KEY, &file_read_key, &mut []);
+        assert!(result == Err(RFIDError::IntegrityError));
+    }
+}
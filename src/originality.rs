@@ -0,0 +1,239 @@
+// src/originality.rs
+// Verification of the NXP originality signature returned by READ_SIG.
+// The signature is an ECDSA signature over the raw UID (no hash) on the secp128r1 curve.
+// Plain 128 bit arithmetic without lookup tables: small, but expect it to take a while on AVR.
+
+// Uncompressed public keys (0x04 || X || Y) published by NXP
+pub const NXP_NTAG21X_PUBLIC_KEY: [u8; 33] = [
+    0x04, 0x49, 0x4E, 0x1A, 0x38, 0x6D, 0x3D, 0x3C, 0xFE, 0x3D, 0xC1, 0x0E, 0x5D, 0xE6, 0x8A, 0x49, 0x9B,
+    0x1C, 0x20, 0x2D, 0xB5, 0xB1, 0x32, 0x39, 0x3E, 0x89, 0xED, 0x19, 0xFE, 0x5B, 0xE8, 0xBC, 0x61,
+];
+pub const NXP_ULTRALIGHT_EV1_PUBLIC_KEY: [u8; 33] = [
+    0x04, 0x90, 0x93, 0x3B, 0xDC, 0xD6, 0xE9, 0x9B, 0x4E, 0x25, 0x5E, 0x3D, 0xA5, 0x53, 0x89, 0xA8, 0x27,
+    0x56, 0x4E, 0x11, 0x71, 0x8E, 0x01, 0x72, 0x92, 0xFA, 0xF2, 0x32, 0x26, 0xA9, 0x66, 0x14, 0xB8,
+];
+
+// secp128r1 domain parameters (SEC 2)
+const P: u128 = 0xFFFFFFFD_FFFFFFFF_FFFFFFFF_FFFFFFFF;
+const B: u128 = 0xE87579C1_1079F43D_D824993C_2CEE5ED3;
+const N: u128 = 0xFFFFFFFE_00000000_75A30D1B_9038A115;
+const GX: u128 = 0x161FF752_8B899B2D_0C28607C_A52C5B86;
+const GY: u128 = 0xCF5AC839_5BAFEB13_C02DA292_DDED7A83;
+
+// Checks a 32 byte READ_SIG answer (r || s) against the UID and an uncompressed public key
+pub fn verify_signature(uid: &[u8], signature: &[u8; 32], public_key: &[u8; 33]) -> bool {
+    if public_key[0] != 0x04 || uid.is_empty() || uid.len() > 16 {
+        return false;
+    }
+    let q = Point {
+        x: be_u128(&public_key[1..17]),
+        y: be_u128(&public_key[17..]),
+        z: 1,
+    };
+    if q.x >= P || q.y >= P || !on_curve(q.x, q.y) {
+        return false;
+    }
+
+    let r = be_u128(&signature[..16]);
+    let s = be_u128(&signature[16..]);
+    if r == 0 || r >= N || s == 0 || s >= N {
+        return false;
+    }
+
+    // The UID is shorter than the group order, so it is used as the message directly
+    let e = be_u128(uid) % N;
+    let w = inv_mod(s, N);
+    let u1 = mul_mod(e, w, N);
+    let u2 = mul_mod(r, w, N);
+
+    let g = Point { x: GX, y: GY, z: 1 };
+    match shamir(u1, &g, u2, &q).to_affine() {
+        Some((x, _)) => x % N == r,
+        None => false,
+    }
+}
+
+fn be_u128(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0u128, |acc, &byte| (acc << 8) | byte as u128)
+}
+
+fn add_mod(a: u128, b: u128, m: u128) -> u128 {
+    let (sum, carry) = a.overflowing_add(b);
+    if carry || sum >= m {
+        sum.wrapping_sub(m)
+    } else {
+        sum
+    }
+}
+
+fn sub_mod(a: u128, b: u128, m: u128) -> u128 {
+    if a >= b {
+        a - b
+    } else {
+        m - (b - a)
+    }
+}
+
+// Double and add, there is no 256 bit product to reduce
+fn mul_mod(a: u128, b: u128, m: u128) -> u128 {
+    let mut result = 0;
+    for i in (0..128).rev() {
+        result = add_mod(result, result, m);
+        if (b >> i) & 1 != 0 {
+            result = add_mod(result, a, m);
+        }
+    }
+    result
+}
+
+// Fermat inversion, `m` is prime
+fn inv_mod(a: u128, m: u128) -> u128 {
+    let exponent = m - 2;
+    let mut result = 1;
+    for i in (0..128).rev() {
+        result = mul_mod(result, result, m);
+        if (exponent >> i) & 1 != 0 {
+            result = mul_mod(result, a, m);
+        }
+    }
+    result
+}
+
+// y^2 = x^3 - 3x + b
+fn on_curve(x: u128, y: u128) -> bool {
+    let x3 = mul_mod(mul_mod(x, x, P), x, P);
+    let rhs = add_mod(sub_mod(x3, mul_mod(3, x, P), P), B, P);
+    mul_mod(y, y, P) == rhs
+}
+
+// Jacobian coordinates, z == 0 is the point at infinity
+#[derive(Clone, Copy)]
+struct Point {
+    x: u128,
+    y: u128,
+    z: u128,
+}
+
+const INFINITY: Point = Point { x: 1, y: 1, z: 0 };
+
+impl Point {
+    // dbl-2001-b, a = -3
+    fn double(&self) -> Point {
+        if self.z == 0 || self.y == 0 {
+            return INFINITY;
+        }
+        let delta = mul_mod(self.z, self.z, P);
+        let gamma = mul_mod(self.y, self.y, P);
+        let beta = mul_mod(self.x, gamma, P);
+        let alpha = mul_mod(
+            3,
+            mul_mod(sub_mod(self.x, delta, P), add_mod(self.x, delta, P), P),
+            P,
+        );
+        let x = sub_mod(mul_mod(alpha, alpha, P), mul_mod(8, beta, P), P);
+        let yz = add_mod(self.y, self.z, P);
+        let z = sub_mod(sub_mod(mul_mod(yz, yz, P), gamma, P), delta, P);
+        let y = sub_mod(
+            mul_mod(alpha, sub_mod(mul_mod(4, beta, P), x, P), P),
+            mul_mod(8, mul_mod(gamma, gamma, P), P),
+            P,
+        );
+        Point { x, y, z }
+    }
+
+    fn add(&self, other: &Point) -> Point {
+        if self.z == 0 {
+            return *other;
+        }
+        if other.z == 0 {
+            return *self;
+        }
+        let z1z1 = mul_mod(self.z, self.z, P);
+        let z2z2 = mul_mod(other.z, other.z, P);
+        let u1 = mul_mod(self.x, z2z2, P);
+        let u2 = mul_mod(other.x, z1z1, P);
+        let s1 = mul_mod(self.y, mul_mod(other.z, z2z2, P), P);
+        let s2 = mul_mod(other.y, mul_mod(self.z, z1z1, P), P);
+        let h = sub_mod(u2, u1, P);
+        let r = sub_mod(s2, s1, P);
+        if h == 0 {
+            return if r == 0 { self.double() } else { INFINITY };
+        }
+
+        let hh = mul_mod(h, h, P);
+        let hhh = mul_mod(hh, h, P);
+        let v = mul_mod(u1, hh, P);
+        let x = sub_mod(sub_mod(mul_mod(r, r, P), hhh, P), add_mod(v, v, P), P);
+        let y = sub_mod(mul_mod(r, sub_mod(v, x, P), P), mul_mod(s1, hhh, P), P);
+        let z = mul_mod(h, mul_mod(self.z, other.z, P), P);
+        Point { x, y, z }
+    }
+
+    fn to_affine(self) -> Option<(u128, u128)> {
+        if self.z == 0 {
+            return None;
+        }
+        let z_inv = inv_mod(self.z, P);
+        let z_inv2 = mul_mod(z_inv, z_inv, P);
+        Some((mul_mod(self.x, z_inv2, P), mul_mod(self.y, mul_mod(z_inv2, z_inv, P), P)))
+    }
+}
+
+// k1 * p1 + k2 * p2 with a single run of doublings
+fn shamir(k1: u128, p1: &Point, k2: u128, p2: &Point) -> Point {
+    let both = p1.add(p2);
+    let mut result = INFINITY;
+    for i in (0..128).rev() {
+        result = result.double();
+        match ((k1 >> i) & 1, (k2 >> i) & 1) {
+            (1, 1) => result = result.add(&both),
+            (1, 0) => result = result.add(p1),
+            (0, 1) => result = result.add(p2),
+            _ => {}
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // secp128r1 ECDSA over the raw UID, made with private key 00112233445566778899AABBCCDDEEFF
+    // and nonce 0F1E2D3C4B5A69788796A5B4C3D2E1F0 by an independent implementation
+    const UID: [u8; 7] = [0x04, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6];
+    const PUBLIC_KEY: [u8; 33] = [
+        0x04, 0x98, 0x64, 0x39, 0xB0, 0xA5, 0xFA, 0xED, 0x88, 0x37, 0xAE, 0x9F, 0x3A, 0xA1, 0x91, 0x7B, 0xD1,
+        0xF1, 0x96, 0x72, 0xC1, 0x53, 0xA2, 0xE2, 0x28, 0x12, 0x93, 0xA1, 0xD6, 0x56, 0x51, 0x4F, 0x81,
+    ];
+    const SIGNATURE: [u8; 32] = [
+        0x66, 0x83, 0x83, 0x29, 0xBF, 0x60, 0xF7, 0x50, 0xFF, 0x4C, 0xED, 0x17, 0x02, 0xF7, 0x6D, 0xF9,
+        0x2A, 0xDF, 0x39, 0x17, 0x35, 0xBE, 0x74, 0xA8, 0xA8, 0x39, 0x1D, 0x50, 0x93, 0xDA, 0x78, 0x6B,
+    ];
+
+    #[test]
+    fn accepts_valid_signature() {
+        assert!(verify_signature(&UID, &SIGNATURE, &PUBLIC_KEY));
+    }
+
+    #[test]
+    fn rejects_other_uid_signature_or_key() {
+        let mut uid = UID;
+        uid[6] ^= 0x01;
+        assert!(!verify_signature(&uid, &SIGNATURE, &PUBLIC_KEY));
+
+        let mut signature = SIGNATURE;
+        signature[31] ^= 0x01;
+        assert!(!verify_signature(&UID, &signature, &PUBLIC_KEY));
+
+        assert!(!verify_signature(&UID, &SIGNATURE, &NXP_NTAG21X_PUBLIC_KEY));
+        assert!(!verify_signature(&UID, &[0u8; 32], &PUBLIC_KEY));
+    }
+
+    #[test]
+    fn nxp_public_keys_are_on_the_curve() {
+        for key in [NXP_NTAG21X_PUBLIC_KEY, NXP_ULTRALIGHT_EV1_PUBLIC_KEY] {
+            assert!(on_curve(be_u128(&key[1..17]), be_u128(&key[17..])));
+        }
+    }
+}
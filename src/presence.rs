@@ -0,0 +1,103 @@
+// src/presence.rs
+// Following one card over time. Each poll wakes the known card with WUPA and selects it by
+// UID, or looks for a new one with WUPA and anticollision, then halts it again so the next
+// poll finds it in the same state. A card has to be missed `debounce` polls in a row before
+// it counts as removed, which hides short dropouts at the edge of the field.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::Uid;
+use crate::errors::RFIDError;
+use crate::rfid_rc522::RfidRc522;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PresenceEvent {
+    CardArrived(Uid),
+    CardStillPresent(Uid),
+    CardRemoved(Uid),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct PresenceTracker {
+    debounce: u8,
+    current: Option<Uid>,
+    misses: u8, // Polls in a row the current card did not answer
+}
+
+impl PresenceTracker {
+    // `debounce` missed polls (at least 1) end the presence of a card
+    pub const fn new(debounce: u8) -> Self {
+        PresenceTracker { debounce: if debounce == 0 { 1 } else { debounce }, current: None, misses: 0 }
+    }
+
+    // The card currently considered present
+    pub fn current(&self) -> Option<&Uid> {
+        self.current.as_ref()
+    }
+
+    // Feeds the UID seen by one poll. No event while no card is present or while a missing
+    // card is being debounced. A different card first ends the presence of the current one,
+    // it arrives with the next poll.
+    pub fn update(&mut self, seen: Option<Uid>) -> Option<PresenceEvent> {
+        match (self.current, seen) {
+            (Some(current), Some(uid)) if current == uid => {
+                self.misses = 0;
+                Some(PresenceEvent::CardStillPresent(uid))
+            }
+            (Some(current), Some(_)) => {
+                self.current = None;
+                self.misses = 0;
+                Some(PresenceEvent::CardRemoved(current))
+            }
+            (Some(current), None) => {
+                self.misses += 1;
+                if self.misses < self.debounce {
+                    return None;
+                }
+                self.current = None;
+                self.misses = 0;
+                Some(PresenceEvent::CardRemoved(current))
+            }
+            (None, Some(uid)) => {
+                self.current = Some(uid);
+                self.misses = 0;
+                Some(PresenceEvent::CardArrived(uid))
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Polls the field once and feeds the result to `tracker`. The card is left halted, use
+    // reactivate to talk to it.
+    pub fn poll_presence<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        tracker: &mut PresenceTracker,
+    ) -> Result<Option<PresenceEvent>, RFIDError> {
+        let seen = match tracker.current() {
+            Some(&uid) => self.reactivate(serial, &uid).ok().map(|_| uid),
+            None => self.wakeup_and_select(serial)?,
+        };
+        if seen.is_some() {
+            self.halt_a(serial).ok();
+        }
+        Ok(tracker.update(seen))
+    }
+
+    // WUPA and full anticollision, None when no card answers
+    fn wakeup_and_select<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<Option<Uid>, RFIDError> {
+        match self.wakeup_a(serial) {
+            Ok(_) | Err(RFIDError::Collision) => {}
+            Err(RFIDError::Timeout) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut uid = Uid::new();
+        Ok(self.picc_select(serial, &mut uid, 0).ok().map(|_| uid))
+    }
+}
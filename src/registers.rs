@@ -53,4 +53,29 @@ pub const PICC_CMD_SEL_CL1: u8 = 0x93;
 pub const PICC_CMD_SEL_CL2: u8 = 0x95;
 pub const PICC_CMD_SEL_CL3: u8 = 0x97;
 pub const PICC_CMD_CT: u8 = 0x88; // Cascade Tag
+pub const PICC_CMD_WUPA: u8 = 0x52; // Wake-UP command, also wakes PICCs in HALT state
+pub const PICC_CMD_HLTA: u8 = 0x50; // HaLT command, Type A. Instructs an ACTIVE PICC to go to state HALT
+pub const PICC_CMD_RATS: u8 = 0xE0; // Request for Answer To Select, activates ISO/IEC 14443-4
+pub const PICC_CMD_PPS: u8 = 0xD0;  // Protocol and Parameter Selection, low nibble is the CID
 
+// MIFARE Classic commands
+pub const PICC_CMD_MF_AUTH_KEY_A: u8 = 0x60; // Perform authentication with Key A
+pub const PICC_CMD_MF_AUTH_KEY_B: u8 = 0x61; // Perform authentication with Key B
+pub const PICC_CMD_MF_READ: u8 = 0x30;       // Reads one 16 byte block from the authenticated sector
+pub const PICC_CMD_MF_WRITE: u8 = 0xA0;      // Writes one 16 byte block to the authenticated sector
+
+// MIFARE Ultralight / NTAG commands (READ and COMPATIBILITY WRITE share the MIFARE Classic codes)
+pub const PICC_CMD_UL_WRITE: u8 = 0xA2;        // Writes one 4 byte page
+pub const PICC_CMD_NTAG_FAST_READ: u8 = 0x3A;  // Reads a range of pages
+pub const PICC_CMD_GET_VERSION: u8 = 0x60;     // Product version information (Ultralight EV1, NTAG)
+pub const PICC_CMD_PWD_AUTH: u8 = 0x1B;        // 32 bit password authentication
+pub const PICC_CMD_READ_SIG: u8 = 0x3C;        // 32 byte ECC originality signature
+pub const PICC_CMD_READ_CNT: u8 = 0x39;        // Reads a 24 bit one-way counter
+pub const PICC_CMD_INCR_CNT: u8 = 0xA5;        // Increments a one-way counter (Ultralight EV1)
+pub const PICC_CMD_CHECK_TEARING: u8 = 0x3E;   // Tearing flag of a counter (Ultralight EV1)
+pub const PICC_CMD_UL_C_AUTH: u8 = 0x1A;       // First step of the Ultralight C 3DES authentication
+pub const PICC_CMD_UL_C_AUTH_CONTINUE: u8 = 0xAF; // Additional frame of the 3DES authentication
+
+// Backdoor commands of Gen1a "magic" cards, sent unencrypted after HLTA
+pub const PICC_CMD_MAGIC_WUPC1: u8 = 0x40; // 7 bit frame
+pub const PICC_CMD_MAGIC_WUPC2: u8 = 0x43;
@@ -1,13 +1,21 @@
 use embedded_hal::spi::SpiBus;
 use embedded_hal::digital::OutputPin;
 use crate::registers::*;
-use crate::card_types::CardType; // Import CardType from separate file
+use crate::commands::*;
+use crate::card_types::{CardType, Uid}; // Import CardType from separate file
+use crate::mifare::{Key, KeyType, MF_BLOCK_SIZE};
 use ufmt::uWrite;
 use crate::errors::RFIDError;
+use crate::iso14443_4::{BitRate, IsoDepParams};
+
+// Software limit for one exchange, the MFRC522 timer normally ends it after 25ms
+const DEFAULT_TIMEOUT_MS: u32 = 100;
 
 pub struct RfidRc522<SPI, CS> {
     spi: SPI,
     cs: CS,
+    timeout_ms: u32,
+    pub(crate) iso_dep: Option<IsoDepParams>, // Set by RATS, cleared by the next select
 }
 
 impl<SPI, CS> RfidRc522<SPI, CS>
@@ -16,7 +24,7 @@ where
     CS: OutputPin<Error = core::convert::Infallible>,
 {
     pub fn new(spi: SPI, cs: CS) -> Self {
-        RfidRc522 { spi, cs }
+        RfidRc522 { spi, cs, timeout_ms: DEFAULT_TIMEOUT_MS, iso_dep: None }
     }
 
     pub fn init<W: ufmt::uWrite>(&mut self, reset_pin: &mut dyn OutputPin<Error = core::convert::Infallible>, serial: &mut W) {
@@ -25,13 +33,13 @@ where
         ufmt::uwriteln!(serial, "CS set high").ok();
     
         reset_pin.set_low().ok();
-        arduino_hal::delay_ms(50);
+        crate::delay::delay_ms(50);
         reset_pin.set_high().ok();
-        arduino_hal::delay_ms(50);
+        crate::delay::delay_ms(50);
     
         // Soft reset
         self.write_register(serial, COMMAND_REG, 0x0F);
-        arduino_hal::delay_ms(50);
+        crate::delay::delay_ms(50);
     
         let version = self.read_register(serial, VERSION_REG);
         ufmt::uwriteln!(serial, "RFID-RC522 Version: 0x{:X}", version).ok();
@@ -40,10 +48,7 @@ where
         self.write_register(serial, TX_MODE_REG, 0x00);
         self.write_register(serial, RX_MODE_REG, 0x00);
         self.write_register(serial, MODE_WIDTH_REG, 0x26);
-        self.write_register(serial, T_MODE_REG, 0x80);
-        self.write_register(serial, T_PRESCALER_REG, 0xA9);
-        self.write_register(serial, T_RELOAD_REG_H, 0x03);
-        self.write_register(serial, T_RELOAD_REG_L, 0xE8);
+        self.reset_timeout(serial);
         self.write_register(serial, TX_ASK_REG, 0x40); // 100% ASK
         self.write_register(serial, MODE_REG, 0x3D);   // CRC preset to 0x6363
         self.antenna_on(serial); // Enable the antenna
@@ -77,7 +82,7 @@ where
             if irq & 0x04 != 0 {
                 break; // CRC calculation complete
             }
-            arduino_hal::delay_ms(1);
+            crate::delay::delay_ms(1);
             timeout -= 1;
         }
     
@@ -94,42 +99,24 @@ where
     
     
     pub fn detect_card_type<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<Option<CardType>, RFIDError> {
-        // Clear any pending interrupts and reset FIFO
-        self.write_register(serial, COMM_IRQ_REG, 0x7F);
-        self.write_register(serial, FIFO_LEVEL_REG, 0x80); // Clear FIFO buffer
-    
         // Send the REQA command to check for a card
-        let reqa_command = 0x26;
-        self.send_command(serial, reqa_command)?;
-    
-        // Wait for a response
-        let response = self.read_response(serial)?;
-        if response.is_none() {
-            return Ok(None); // No card detected if no response
+        let mut atqa = [0u8; 2];
+        let mut atqa_size = 2;
+        match self.picc_reqa_or_wupa(serial, REQA, &mut atqa, &mut atqa_size) {
+            Err(RFIDError::Timeout) => return Ok(None), // No card detected if no response
+            Err(err) => return Err(err),
+            Ok(()) => {}
         }
-    
-        // Check FIFO level to see if we received a valid response
-        let fifo_level = self.read_register(serial, FIFO_LEVEL_REG);
-        if fifo_level < 2 {
-            return Ok(None); // No valid response, so no card detected
-        }
-    
-        // Read the SAK (Select Acknowledge) from the FIFO
-        let sak = self.get_sak(serial)?;
-    
-        // Determine the card type based on SAK
-        let card_type = match sak {
-            0x04 => CardType::Mifare1K,
-            0x08 => CardType::Mifare4K,
-            0x00 => CardType::MifareUltralight,
-            _ => CardType::Unknown,
-        };
-    
-        // Only return a detected card type if SAK is valid and meaningful
-        if sak != 0x00 && sak != 0xFF {
-            Ok(Some(card_type))
-        } else {
-            Ok(None) // No valid card detected
+
+        // The SAK (Select Acknowledge) is only sent in answer to SELECT, so select the card.
+        // It is left in the ACTIVE state.
+        let mut uid = Uid::new();
+        self.picc_select(serial, &mut uid, 0)?;
+
+        // Determine the card type based on SAK, Type 2 Tags all share SAK 0x00
+        match CardType::from_sak(uid.sak) {
+            CardType::MifareUltralight => Ok(Some(self.identify_type2(serial, &uid)?)),
+            card_type => Ok(Some(card_type)),
         }
     }
 
@@ -144,19 +131,15 @@ where
         // Reset ModWidthReg
         self.write_register(serial, MODE_WIDTH_REG, 0x26);
 
-        // Send the REQA command to check for a card
-        //let reqa_command = 0x26;
-        //self.send_command(serial, reqa_command)?;
-        self.request_a(serial);
-        
-        // Wait for a response
-        let response = self.read_response(serial)?;
-        if response.is_none() {
-            return Ok(false); // No card detected if no response
+        // Send the REQA command to check for a card. Only PICCs in IDLE state answer, so a
+        // PICC halted with halt_a is not reported again until it re-enters the field or is
+        // woken up with wakeup_a. PresenceTracker follows a card over time.
+        match self.request_a(serial) {
+            Ok(present) => Ok(present),
+            Err(RFIDError::Collision) => Ok(true), // Differing ATQAs of several PICCs
+            Err(RFIDError::Timeout) => Ok(false),  // No card detected if no response
+            Err(err) => Err(err),
         }
-    
-        // Card detected based on FIFO level response
-        Ok(true)
     }
 
     // Correct implementation of REQA or WUPA as per MFRC522 library (with minimal changes)
@@ -167,35 +150,22 @@ where
         buffer: &mut [u8; 2], // Buffer to store ATQA response
         buffer_size: &mut u8,  // Buffer size (should be at least 2 bytes)
     ) -> Result<(), RFIDError> {
-        let valid_bits = 7; // REQA/WUPA only requires 7 bits for the last byte (short frame format)
+        let mut valid_bits = 7; // REQA/WUPA only requires 7 bits for the last byte (short frame format)
 
         // Ensure the buffer has space for ATQA (2 bytes)
         if buffer.is_empty() || *buffer_size < 2 {
             return Err(RFIDError::InvalidResponse); // ATQA must be 2 bytes
         }
 
-        // Send REQA or WUPA command using the FIFO (this is the correct approach as in their code)
-        self.send_command(serial, command);
-
-        // Wait for the response
-        let response = self.read_response(serial)?;
-        if response.is_none() {
-            return Err(RFIDError::Timeout); // Timeout if no response
-        }
+        // ValuesAfterColl=1 => Bits received after collision are cleared
+        self.clear_register_bit_mask(serial, COLL_REG, 0x80);
 
-        // Read the ATQA response (should be exactly 2 bytes)
-        let fifo_level = self.read_register(serial, FIFO_LEVEL_REG);
-        if fifo_level >= 2 {
-            for i in 0..2 {
-                buffer[i] = self.read_register(serial, FIFO_DATA_REG);
-            }
-        } else {
-            return Err(RFIDError::Timeout); // No valid response in FIFO
-        }
+        // Send REQA or WUPA as a short frame and read back the ATQA
+        let received = self.transceive_data(serial, &[command], buffer, &mut valid_bits, 0, false)?;
 
-        // Check if the ATQA response is valid (not zero)
-        if buffer[0] == 0x00 || buffer[1] == 0x00 {
-            return Err(RFIDError::InvalidResponse); // Invalid ATQA response
+        // ATQA must be exactly 2 whole bytes (one of them is usually 0x00)
+        if received != 2 || valid_bits != 0 {
+            return Err(RFIDError::InvalidResponse);
         }
 
         // Set buffer size to 2 (as expected)
@@ -213,7 +183,52 @@ where
         self.picc_reqa_or_wupa(serial, 0x26, &mut buffer, &mut buffer_size)?;
 
         // Check if the response is valid (ATQA is not zero)
-        Ok(buffer[0] != 0x00 && buffer[1] != 0x00)
+        Ok(buffer[0] != 0x00 || buffer[1] != 0x00)
+    }
+
+    // Sends HLTA (0x50 0x00 + CRC_A), putting the selected PICC into the HALT state where it
+    // only answers WUPA. The PICC acknowledges by not answering at all.
+    pub fn halt_a<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<(), RFIDError> {
+        let mut command = [PICC_CMD_HLTA, 0x00, 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &command[..2], &mut crc)?;
+        command[2] = crc[0];
+        command[3] = crc[1];
+
+        // A halted PICC leaves layer 4
+        self.iso_dep = None;
+        let mut valid_bits = 0;
+        let result = self.transceive_data(serial, &command, &mut [], &mut valid_bits, 0, false);
+        // HLTA goes out at the PPS rate, the next WUPA and anticollision run at 106 kbit/s
+        self.set_bit_rates(serial, BitRate::Kbps106, BitRate::Kbps106);
+        match result {
+            Err(RFIDError::Timeout) => Ok(()),
+            Ok(_) => Err(RFIDError::InvalidResponse),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Sends WUPA, which unlike REQA is also answered by PICCs in the HALT state
+    pub fn wakeup_a<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<bool, RFIDError> {
+        let mut buffer = [0u8; 2];
+        let mut buffer_size = 2;
+        self.picc_reqa_or_wupa(serial, PICC_CMD_WUPA, &mut buffer, &mut buffer_size)?;
+        Ok(buffer[0] != 0x00 || buffer[1] != 0x00)
+    }
+
+    // Wakes up a PICC (also from HALT) with WUPA and selects it by its known UID, without
+    // running anticollision. Fails if that PICC is no longer in the field.
+    pub fn reactivate<W: ufmt::uWrite>(&mut self, serial: &mut W, uid: &Uid) -> Result<(), RFIDError> {
+        match self.wakeup_a(serial) {
+            Ok(_) | Err(RFIDError::Collision) => {}
+            Err(err) => return Err(err),
+        }
+        let mut selected = *uid;
+        self.picc_select(serial, &mut selected, uid.size * 8)?;
+        if selected.as_bytes() != uid.as_bytes() {
+            return Err(RFIDError::InvalidResponse);
+        }
+        Ok(())
     }
 
     pub fn read_card_serial<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<Option<[u8; 10]>, RFIDError> {
@@ -239,136 +254,424 @@ where
         uid: &mut [u8; 10],
         valid_bits: u8,
     ) -> Result<u8, RFIDError> {
-        let mut cascade_level = 1;
+        let mut selected = Uid::new();
+        selected.bytes = *uid;
+        selected.size = match valid_bits {
+            0..=32 => 4,
+            33..=56 => 7,
+            _ => 10,
+        };
+
+        self.picc_select(serial, &mut selected, valid_bits)?;
+        *uid = selected.bytes;
+        Ok(selected.sak)
+    }
+
+    // Transmits SELECT/ANTICOLLISION commands to select a single PICC (port of PICC_Select).
+    // `valid_bits` is the number of known UID bits already in `uid`, 0 to run full anticollision.
+    // On success `uid` holds the complete UID, its size and the SAK.
+    pub fn picc_select<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        uid: &mut Uid,
+        valid_bits: u8,
+    ) -> Result<(), RFIDError> {
+        if valid_bits > 80 {
+            return Err(RFIDError::InvalidResponse);
+        }
+        // A newly selected PICC starts at layer 3
+        self.iso_dep = None;
+
+        // ValuesAfterColl=1 => Bits received after collision are cleared
+        self.clear_register_bit_mask(serial, COLL_REG, 0x80);
+
+        let mut cascade_level: u8 = 1;
         let mut uid_complete = false;
-        let mut current_level_known_bits = valid_bits;
-        let mut tx_last_bits: u8 = 0;
-        let mut sak: u8 = 0;
-        let mut uid_index = 0;
-    
         while !uid_complete {
-            let (sel_command, uid_start) = match cascade_level {
-                1 => (PICC_CMD_SEL_CL1, 0),
-                2 => (PICC_CMD_SEL_CL2, 3),
-                3 => (PICC_CMD_SEL_CL3, 6),
-                _ => return Err(RFIDError::InvalidResponse),
+            let (sel_command, uid_index, use_cascade_tag) = match cascade_level {
+                1 => (PICC_CMD_SEL_CL1, 0usize, valid_bits != 0 && uid.size > 4),
+                2 => (PICC_CMD_SEL_CL2, 3, valid_bits != 0 && uid.size > 7),
+                3 => (PICC_CMD_SEL_CL3, 6, false),
+                _ => return Err(RFIDError::Error),
             };
-    
-            // Prepare buffer
+
+            // Prepare buffer: SEL, NVB, up to 4 UID bytes (or CT + 3 bytes), BCC, CRC_A
             let mut buffer = [0u8; 9];
             buffer[0] = sel_command;
+            let mut current_level_known_bits = valid_bits.saturating_sub(8 * uid_index as u8);
             let mut index = 2;
-    
+
             // If using Cascade Tag
-            if cascade_level > 1 && uid[uid_start - 1] == PICC_CMD_CT {
+            if use_cascade_tag {
                 buffer[index] = PICC_CMD_CT;
                 index += 1;
             }
-    
-            // Copy UID bytes into buffer
-            let bytes_to_copy = current_level_known_bits / 8 + (current_level_known_bits % 8 != 0) as u8;
-            for i in 0..bytes_to_copy.min(4 - (index as u8)) {
-                buffer[index] = uid[uid_start + i as usize];
-                index += 1;
+
+            // Copy the known UID bytes of this cascade level into buffer
+            let max_bytes = if use_cascade_tag { 3 } else { 4 };
+            let bytes_to_copy = (current_level_known_bits as usize).div_ceil(8).min(max_bytes);
+            buffer[index..index + bytes_to_copy]
+                .copy_from_slice(&uid.bytes[uid_index..uid_index + bytes_to_copy]);
+            if use_cascade_tag {
+                current_level_known_bits += 8;
             }
-    
-            // Set NVB (Number of Valid Bits)
-            buffer[1] = ((index as u8) << 4) | tx_last_bits;
-    
-            // Transceive and handle response
-            let mut response_buffer = [0u8; 3];
-            let mut response_length = 3; // Ensure this is passed as a mutable reference
-            let result = self.transceive(
-                serial,
-                &buffer[..index as usize + 1],
-                &mut response_buffer,
-                &mut response_length,
-            );
-    
-            if let Err(err) = result {
-                if err == RFIDError::Collision {
-                    let coll_pos = self.read_register(serial, COLL_REG) & 0x1F;
-                    if coll_pos == 0 {
-                        return Err(RFIDError::Collision); // Collision but no valid position
-                    }
-                    current_level_known_bits = coll_pos;
+
+            // Repeat anticollision until all 32 bits of this cascade level are known, then SELECT
+            let mut tx_last_bits: u8 = 0;
+            let mut response_length = 0;
+            let mut select_done = false;
+            while !select_done {
+                let buffer_used;
+                let response_start;
+                if current_level_known_bits >= 32 {
+                    buffer[1] = 0x70; // NVB - 7 whole bytes
+                    buffer[6] = buffer[2] ^ buffer[3] ^ buffer[4] ^ buffer[5]; // BCC
+                    let mut crc = [0u8; 2];
+                    self.pcd_calculate_crc(serial, &buffer[..7], &mut crc)?;
+                    buffer[7] = crc[0];
+                    buffer[8] = crc[1];
+                    tx_last_bits = 0;
+                    buffer_used = 9;
+                    response_start = 6; // SAK + CRC_A are stored over BCC and CRC_A
                 } else {
-                    return Err(err);
+                    tx_last_bits = current_level_known_bits % 8;
+                    let index = 2 + (current_level_known_bits / 8) as usize;
+                    buffer[1] = ((index as u8) << 4) + tx_last_bits;
+                    buffer_used = index + (tx_last_bits != 0) as usize;
+                    response_start = index;
                 }
-            } else {
-                if current_level_known_bits >= 32 {
-                    // Check SAK
-                    sak = response_buffer[0];
-                    if sak & 0x04 != 0 {
-                        cascade_level += 1;
-                    } else {
-                        uid_complete = true;
+
+                // The first received bit is stored in the bit position after the last sent bit
+                let rx_align = tx_last_bits;
+                let response_max = if current_level_known_bits >= 32 { 3 } else { buffer.len() - response_start };
+                let mut response = [0u8; 9];
+                response[0] = buffer[response_start];
+
+                let result = self.transceive_data(
+                    serial,
+                    &buffer[..buffer_used],
+                    &mut response[..response_max],
+                    &mut tx_last_bits,
+                    rx_align,
+                    false,
+                );
+                if result.is_ok() || result == Err(RFIDError::Collision) {
+                    buffer[response_start..response_start + response_max]
+                        .copy_from_slice(&response[..response_max]);
+                }
+
+                match result {
+                    Err(RFIDError::Collision) => {
+                        let value_of_coll_reg = self.read_register(serial, COLL_REG);
+                        if value_of_coll_reg & 0x20 != 0 {
+                            return Err(RFIDError::Collision); // CollPosNotValid - can't continue
+                        }
+                        let mut collision_pos = value_of_coll_reg & 0x1F; // Values 0-31, 0 means bit 32
+                        if collision_pos == 0 {
+                            collision_pos = 32;
+                        }
+                        if collision_pos <= current_level_known_bits {
+                            return Err(RFIDError::Error); // No progress, should never happen
+                        }
+                        // Choose the PICC with the bit set at the collision position
+                        current_level_known_bits = collision_pos;
+                        let count = current_level_known_bits % 8;
+                        let check_bit = (current_level_known_bits - 1) % 8;
+                        let index = 1 + (current_level_known_bits / 8) as usize + (count != 0) as usize;
+                        buffer[index] |= 1 << check_bit;
+                    }
+                    Err(err) => return Err(err),
+                    Ok(len) => {
+                        response_length = len;
+                        if current_level_known_bits >= 32 {
+                            select_done = true; // SELECT answered with SAK
+                        } else {
+                            current_level_known_bits = 32; // All bits known, run SELECT next
+                        }
                     }
-                } else {
-                    current_level_known_bits = 32; // Continue anti-collision for full UID
                 }
             }
+
+            // Copy the UID bytes of this cascade level, skipping the Cascade Tag
+            let (index, bytes_to_copy) = if buffer[2] == PICC_CMD_CT { (3, 3) } else { (2, 4) };
+            uid.bytes[uid_index..uid_index + bytes_to_copy].copy_from_slice(&buffer[index..index + bytes_to_copy]);
+
+            // Check the SAK response: 1 byte + CRC_A
+            if response_length != 3 || tx_last_bits != 0 {
+                return Err(RFIDError::InvalidResponse);
+            }
+            let mut crc = [0u8; 2];
+            self.pcd_calculate_crc(serial, &buffer[6..7], &mut crc)?;
+            if buffer[7] != crc[0] || buffer[8] != crc[1] {
+                return Err(RFIDError::CrcError);
+            }
+
+            if buffer[6] & 0x04 != 0 {
+                cascade_level += 1; // Cascade bit set - UID not complete yet
+            } else {
+                uid_complete = true;
+                uid.sak = buffer[6];
+            }
         }
-    
-        Ok(sak)
+
+        uid.size = 3 * cascade_level + 1;
+        Ok(())
     }
-    
-    fn transceive<W: ufmt::uWrite>(
+
+    // Wakes up and selects the PICC again using its known UID
+    pub(crate) fn reselect<W: ufmt::uWrite>(&mut self, serial: &mut W, uid: &Uid) -> Result<(), RFIDError> {
+        self.stop_crypto1(serial);
+        self.reactivate(serial, uid)
+    }
+
+    // Authenticates the sector containing `block_addr` with a MIFARE Classic key.
+    // The PICC must be selected; on success the MFRC522 encrypts all further communication.
+    pub fn mifare_authenticate<W: ufmt::uWrite>(
         &mut self,
         serial: &mut W,
-        send_buffer: &[u8],
-        receive_buffer: &mut [u8],
-        receive_length: &mut usize,
+        key_type: KeyType,
+        block_addr: u8,
+        key: &Key,
+        uid: &Uid,
     ) -> Result<(), RFIDError> {
-        // Write data to FIFO
-        for &byte in send_buffer {
-            self.write_register(serial, FIFO_DATA_REG, byte);
+        let size = uid.size as usize;
+        if !(4..=10).contains(&size) {
+            return Err(RFIDError::InvalidResponse);
         }
-        // Initiate transceive command
-        self.write_register(serial, COMMAND_REG, TRANSCEIVE);
-        // Wait for response or timeout
-        let mut timeout = 100;
-        while timeout > 0 {
-            let irq = self.read_register(serial, COMM_IRQ_REG);
-            if irq & 0x30 != 0 {
-                // Process received data
-                let fifo_level = self.read_register(serial, FIFO_LEVEL_REG) as usize;
-                for i in 0..fifo_level.min(receive_buffer.len()) {
-                    receive_buffer[i] = self.read_register(serial, FIFO_DATA_REG);
+
+        // Command, block address, 6 key bytes and the last 4 UID bytes
+        let mut send_data = [0u8; 12];
+        send_data[0] = key_type.auth_command();
+        send_data[1] = block_addr;
+        send_data[2..8].copy_from_slice(&key.0);
+        send_data[8..12].copy_from_slice(&uid.bytes[size - 4..size]);
+
+        let mut valid_bits = 0;
+        self.communicate_with_picc(serial, PCD_AUTH, 0x10, &send_data, &mut [], &mut valid_bits, 0, false)?;
+
+        // MFCrypto1On is only set after a successful authentication
+        if self.read_register(serial, STATUS2_REG) & 0x08 == 0 {
+            return Err(RFIDError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    // Leaves the authenticated state, must be called after communicating with an authenticated PICC
+    pub fn stop_crypto1<W: ufmt::uWrite>(&mut self, serial: &mut W) {
+        self.clear_register_bit_mask(serial, STATUS2_REG, 0x08);
+    }
+
+    // Reads one 16 byte block from an authenticated MIFARE Classic sector
+    pub fn mifare_read<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        block_addr: u8,
+    ) -> Result<[u8; MF_BLOCK_SIZE], RFIDError> {
+        let mut command = [PICC_CMD_MF_READ, block_addr, 0, 0];
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, &command[..2], &mut crc)?;
+        command[2] = crc[0];
+        command[3] = crc[1];
+
+        // 16 data bytes + CRC_A
+        let mut buffer = [0u8; MF_BLOCK_SIZE + 2];
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &command, &mut buffer, &mut valid_bits, 0, true)?;
+        if received != buffer.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+
+        let mut block = [0u8; MF_BLOCK_SIZE];
+        block.copy_from_slice(&buffer[..MF_BLOCK_SIZE]);
+        Ok(block)
+    }
+
+    // Writes one 16 byte block to an authenticated MIFARE Classic sector
+    pub fn mifare_write<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        block_addr: u8,
+        data: &[u8; MF_BLOCK_SIZE],
+    ) -> Result<(), RFIDError> {
+        // Step 1: Tell the PICC we want to write to block `block_addr`
+        self.mifare_transceive(serial, &[PICC_CMD_MF_WRITE, block_addr], false)?;
+        // Step 2: Transfer the data
+        self.mifare_transceive(serial, data, false)
+    }
+
+    // Sends a MIFARE command with CRC_A appended and checks the 4 bit ACK (port of PCD_MIFARE_Transceive).
+    // With `accept_timeout` a missing answer counts as success.
+    pub(crate) fn mifare_transceive<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        send_data: &[u8],
+        accept_timeout: bool,
+    ) -> Result<(), RFIDError> {
+        // Up to 16 bytes of data + CRC_A
+        if send_data.len() > MF_BLOCK_SIZE {
+            return Err(RFIDError::NoRoom);
+        }
+        let len = send_data.len();
+        let mut buffer = [0u8; MF_BLOCK_SIZE + 2];
+        buffer[..len].copy_from_slice(send_data);
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, send_data, &mut crc)?;
+        buffer[len] = crc[0];
+        buffer[len + 1] = crc[1];
+
+        let mut back = [0u8; 1];
+        let mut valid_bits = 0;
+        match self.transceive_data(serial, &buffer[..len + 2], &mut back, &mut valid_bits, 0, false) {
+            Err(RFIDError::Timeout) if accept_timeout => Ok(()),
+            Err(err) => Err(err),
+            Ok(received) => {
+                // The PICC must reply with a 4 bit ACK
+                if received != 1 || valid_bits != 4 {
+                    return Err(RFIDError::Error);
+                }
+                if back[0] & 0x0F != 0x0A {
+                    return Err(RFIDError::Nak(back[0] & 0x0F));
                 }
-                *receive_length = fifo_level;
-                return Ok(());
+                Ok(())
             }
-            arduino_hal::delay_ms(1);
-            timeout -= 1;
         }
-        Err(RFIDError::Timeout)
     }
-    
-    fn send_command<W: ufmt::uWrite>(&mut self, serial: &mut W, command: u8) -> Result<(), RFIDError> {
-        // Write the command to FIFO and set TRANSCEIVE mode
-        self.write_register(serial, FIFO_DATA_REG, command);
-        self.write_register(serial, COMMAND_REG, TRANSCEIVE);
-        self.write_register(serial, BIT_FRAMING_REG, 0x87); // Start transmission
-        Ok(())
+
+    // Sends `send_data` to the PICC and reads the answer into `back_data` (port of PCD_TransceiveData).
+    // `valid_bits` holds the number of valid bits in the last sent byte (0 = whole byte) and
+    // returns the number of valid bits in the last received byte.
+    pub(crate) fn transceive_data<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        send_data: &[u8],
+        back_data: &mut [u8],
+        valid_bits: &mut u8,
+        rx_align: u8,
+        check_crc: bool,
+    ) -> Result<usize, RFIDError> {
+        // RxIRq and IdleIRq
+        self.communicate_with_picc(serial, PCD_TRANSCEIVE, 0x30, send_data, back_data, valid_bits, rx_align, check_crc)
     }
 
-    fn read_response<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<Option<u8>, RFIDError> {
-        let mut timeout = 100;
-        while timeout > 0 {
+    // Runs a MFRC522 command that exchanges data with the PICC (port of PCD_CommunicateWithPICC)
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn communicate_with_picc<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        command: u8,
+        wait_irq: u8,
+        send_data: &[u8],
+        back_data: &mut [u8],
+        valid_bits: &mut u8,
+        rx_align: u8,
+        check_crc: bool,
+    ) -> Result<usize, RFIDError> {
+        let tx_last_bits = *valid_bits;
+        let bit_framing = (rx_align << 4) + tx_last_bits; // RxAlign = BitFramingReg[6..4], TxLastBits = BitFramingReg[2..0]
+
+        self.write_register(serial, COMMAND_REG, PCD_IDLE); // Stop any active command
+        self.write_register(serial, COMM_IRQ_REG, 0x7F); // Clear all seven interrupt request bits
+        self.write_register(serial, FIFO_LEVEL_REG, 0x80); // Flush FIFO
+        for &byte in send_data {
+            self.write_register(serial, FIFO_DATA_REG, byte);
+        }
+        self.write_register(serial, BIT_FRAMING_REG, bit_framing);
+        self.write_register(serial, COMMAND_REG, command);
+        if command == PCD_TRANSCEIVE {
+            self.set_register_bit_mask(serial, BIT_FRAMING_REG, 0x80); // StartSend=1, transmission of data starts
+        }
+
+        // Wait for the command to complete, TimerIRq is set when the timer runs out
+        let mut timeout = self.timeout_ms;
+        loop {
             let irq = self.read_register(serial, COMM_IRQ_REG);
-            if irq & 0x30 != 0 {
-                return Ok(Some(irq));
+            if irq & wait_irq != 0 {
+                break;
+            }
+            if irq & 0x01 != 0 {
+                return Err(RFIDError::Timeout); // Nothing received
             }
-            arduino_hal::delay_ms(1);
             timeout -= 1;
+            if timeout == 0 {
+                return Err(RFIDError::Timeout);
+            }
+            crate::delay::delay_ms(1);
+        }
+
+        // Stop now if any errors except collisions were detected (BufferOvfl ParityErr ProtocolErr)
+        let error = self.read_register(serial, ERROR_REG);
+        if error & 0x13 != 0 {
+            return Err(RFIDError::CommunicationError);
+        }
+
+        let mut back_len = 0;
+        if !back_data.is_empty() {
+            let n = self.read_register(serial, FIFO_LEVEL_REG) as usize;
+            if n > back_data.len() {
+                return Err(RFIDError::NoRoom);
+            }
+            for i in 0..n {
+                let value = self.read_register(serial, FIFO_DATA_REG);
+                if i == 0 && rx_align != 0 {
+                    // Only update bit positions rx_align..7 of the first byte
+                    let mask = 0xFFu8 << rx_align;
+                    back_data[0] = (back_data[0] & !mask) | (value & mask);
+                } else {
+                    back_data[i] = value;
+                }
+            }
+            back_len = n;
+            *valid_bits = self.read_register(serial, CONTROL_REG) & 0x07; // RxLastBits
         }
-        Ok(None) // Timeout if no response
+
+        // Collisions are reported after reading the FIFO so the caller can use the received bits
+        if error & 0x08 != 0 {
+            return Err(RFIDError::Collision);
+        }
+
+        if !back_data.is_empty() && check_crc {
+            // A 4 bit response is a MIFARE NAK
+            if back_len == 1 && *valid_bits == 4 {
+                return Err(RFIDError::Nak(back_data[0] & 0x0F));
+            }
+            // Need at least the CRC_A value and all 8 bits of the last byte
+            if back_len < 2 || *valid_bits != 0 {
+                return Err(RFIDError::CrcError);
+            }
+            let mut crc = [0u8; 2];
+            self.pcd_calculate_crc(serial, &back_data[..back_len - 2], &mut crc)?;
+            if back_data[back_len - 2] != crc[0] || back_data[back_len - 1] != crc[1] {
+                return Err(RFIDError::CrcError);
+            }
+        }
+
+        Ok(back_len)
+    }
+
+    // Programs the timer that ends an exchange with TimerIRq when the PICC stays silent for `us`
+    // microseconds. TAuto starts it at the end of each transmission.
+    pub(crate) fn set_timeout<W: uWrite>(&mut self, serial: &mut W, us: u32) {
+        // 13.56 MHz / (2 * prescaler + 1): 25us ticks reach 1.6s, 604us ticks up to 39s
+        let (prescaler, ticks) = if us <= 1_600_000 {
+            (0x0A9u16, us.div_ceil(25))
+        } else {
+            (0xFFF, us.div_ceil(604))
+        };
+        let ticks = ticks.clamp(1, 0xFFFF) as u16;
+        self.write_register(serial, T_MODE_REG, 0x80 | (prescaler >> 8) as u8); // TAuto, TPrescaler_Hi
+        self.write_register(serial, T_PRESCALER_REG, prescaler as u8);
+        self.write_register(serial, T_RELOAD_REG_H, (ticks >> 8) as u8);
+        self.write_register(serial, T_RELOAD_REG_L, ticks as u8);
+        self.timeout_ms = us / 1000 + DEFAULT_TIMEOUT_MS;
     }
 
-    fn get_sak<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<u8, RFIDError> {
-        // Logic to communicate and read the SAK byte from the card
-        Ok(self.read_register(serial, FIFO_DATA_REG)) // Replace with actual SAK read logic
+    // Back to the 25ms timeout set by init
+    pub(crate) fn reset_timeout<W: uWrite>(&mut self, serial: &mut W) {
+        self.write_register(serial, T_MODE_REG, 0x80);
+        self.write_register(serial, T_PRESCALER_REG, 0xA9);
+        self.write_register(serial, T_RELOAD_REG_H, 0x03);
+        self.write_register(serial, T_RELOAD_REG_L, 0xE8);
+        self.timeout_ms = DEFAULT_TIMEOUT_MS;
     }
 
     fn antenna_on<W: uWrite>(&mut self, serial: &mut W) {
@@ -384,7 +687,17 @@ where
         ufmt::uwriteln!(serial, "Antenna gain set to maximum").ok();
     }
 
-    fn write_register<W: uWrite>(&mut self, _serial: &mut W, address: u8, value: u8) {
+    fn set_register_bit_mask<W: uWrite>(&mut self, serial: &mut W, address: u8, mask: u8) {
+        let current = self.read_register(serial, address);
+        self.write_register(serial, address, current | mask);
+    }
+
+    fn clear_register_bit_mask<W: uWrite>(&mut self, serial: &mut W, address: u8, mask: u8) {
+        let current = self.read_register(serial, address);
+        self.write_register(serial, address, current & !mask);
+    }
+
+    pub(crate) fn write_register<W: uWrite>(&mut self, _serial: &mut W, address: u8, value: u8) {
         let buffer = [address & 0x7F, value];
         let mut read_buffer = [0u8; 2];
         self.cs.set_low().ok();
@@ -392,7 +705,7 @@ where
         self.cs.set_high().ok();
     }
 
-    fn read_register<W: uWrite>(&mut self, _serial: &mut W, address: u8) -> u8 {
+    pub(crate) fn read_register<W: uWrite>(&mut self, _serial: &mut W, address: u8) -> u8 {
         let buffer = [address | 0x80, 0x00];
         let mut read_buffer = [0u8; 2];
         self.cs.set_low().ok();
@@ -0,0 +1,461 @@
+// src/type2.rs
+// NFC Forum Type 2 Tag operation: Capability Container, TLV blocks and the NDEF message.
+// The data area starts at page 4. Areas reserved by Lock Control and Memory Control TLVs
+// are skipped when reading and writing, everything else is read and written in order.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::card_types::CardType;
+use crate::errors::RFIDError;
+use crate::mifare::MF_BLOCK_SIZE;
+use crate::rfid_rc522::RfidRc522;
+use crate::ultralight::UL_PAGE_SIZE;
+
+pub const CC_PAGE: u8 = 3;
+pub const CC_MAGIC: u8 = 0xE1;
+pub const CC_VERSION_1_0: u8 = 0x10;
+pub const CC_ACCESS_GRANTED: u8 = 0x00;
+pub const CC_ACCESS_DENIED: u8 = 0x0F;
+
+// TLV block types
+pub const TLV_NULL: u8 = 0x00;
+pub const TLV_LOCK_CONTROL: u8 = 0x01;
+pub const TLV_MEMORY_CONTROL: u8 = 0x02;
+pub const TLV_NDEF: u8 = 0x03;
+pub const TLV_PROPRIETARY: u8 = 0xFD;
+pub const TLV_TERMINATOR: u8 = 0xFE;
+
+// First byte of the data area (page 4)
+pub const DATA_AREA_START: u16 = 16;
+
+// Lock Control and Memory Control TLVs we keep track of
+const MAX_RESERVED_AREAS: usize = 4;
+
+// Size of the NDEF data area (user memory) of a Type 2 Tag, as written to the CC
+pub fn data_area_size(card_type: &CardType) -> Option<u16> {
+    match card_type {
+        CardType::MifareUltralight
+        | CardType::MifareUltralightEv1Mf0ul11
+        | CardType::Ntag210 => Some(48),
+        CardType::MifareUltralightC => Some(144),
+        CardType::MifareUltralightEv1Mf0ul21 | CardType::Ntag212 => Some(128),
+        CardType::Ntag213 => Some(144),
+        CardType::Ntag215 => Some(496),
+        CardType::Ntag216 | CardType::NtagI2c1K | CardType::NtagI2cPlus1K => Some(872),
+        // NTAG I2C 2K memory above page 255 needs SECTOR_SELECT, which is not supported
+        _ => None,
+    }
+}
+
+// Page level access to a Type 2 Tag. Implemented for the reader by Type2Tag; implement it
+// over a memory image to run the NDEF code without a tag.
+pub trait Type2Memory {
+    // Reads 4 pages starting at `page`
+    fn read_pages(&mut self, page: u8) -> Result<[u8; MF_BLOCK_SIZE], RFIDError>;
+    fn write_page(&mut self, page: u8, data: &[u8; UL_PAGE_SIZE]) -> Result<(), RFIDError>;
+}
+
+// A Type 2 Tag in the field of the reader
+pub struct Type2Tag<'a, SPI, CS, W> {
+    rfid: &'a mut RfidRc522<SPI, CS>,
+    serial: &'a mut W,
+}
+
+impl<'a, SPI, CS, W> Type2Tag<'a, SPI, CS, W> {
+    pub fn new(rfid: &'a mut RfidRc522<SPI, CS>, serial: &'a mut W) -> Self {
+        Type2Tag { rfid, serial }
+    }
+}
+
+impl<SPI, CS, W> Type2Memory for Type2Tag<'_, SPI, CS, W>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+    W: ufmt::uWrite,
+{
+    fn read_pages(&mut self, page: u8) -> Result<[u8; MF_BLOCK_SIZE], RFIDError> {
+        self.rfid.ultralight_read(self.serial, page)
+    }
+
+    fn write_page(&mut self, page: u8, data: &[u8; UL_PAGE_SIZE]) -> Result<(), RFIDError> {
+        self.rfid.ultralight_write(self.serial, page, data)
+    }
+}
+
+// Capability Container (page 3)
+#[derive(Clone, Copy, PartialEq)]
+pub struct CapabilityContainer {
+    pub version: u8,         // Major version in the high nibble, minor in the low nibble
+    pub data_area_size: u16, // Bytes, stored on the tag divided by 8
+    pub read_access: u8,
+    pub write_access: u8,
+}
+
+impl CapabilityContainer {
+    pub fn new(data_area_size: u16) -> Self {
+        CapabilityContainer {
+            version: CC_VERSION_1_0,
+            data_area_size,
+            read_access: CC_ACCESS_GRANTED,
+            write_access: CC_ACCESS_GRANTED,
+        }
+    }
+
+    // Rejects pages without the NDEF magic number or with an unsupported major version
+    pub fn parse(page: &[u8; UL_PAGE_SIZE]) -> Result<Self, RFIDError> {
+        if page[0] != CC_MAGIC || page[1] >> 4 != CC_VERSION_1_0 >> 4 {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+        Ok(CapabilityContainer {
+            version: page[1],
+            data_area_size: page[2] as u16 * 8,
+            read_access: page[3] >> 4,
+            write_access: page[3] & 0x0F,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; UL_PAGE_SIZE] {
+        [
+            CC_MAGIC,
+            self.version,
+            (self.data_area_size / 8) as u8,
+            (self.read_access << 4) | (self.write_access & 0x0F),
+        ]
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.read_access == CC_ACCESS_GRANTED
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.write_access == CC_ACCESS_GRANTED
+    }
+}
+
+// Usable bytes of the data area, with the areas reserved by Lock/Memory Control TLVs
+#[derive(Clone, Copy, PartialEq)]
+pub struct Type2Layout {
+    pub cc: CapabilityContainer,
+    reserved: [(u16, u16); MAX_RESERVED_AREAS], // (start, length) in bytes from page 0
+    reserved_count: usize,
+}
+
+impl Type2Layout {
+    pub fn new(cc: CapabilityContainer) -> Self {
+        Type2Layout { cc, reserved: [(0, 0); MAX_RESERVED_AREAS], reserved_count: 0 }
+    }
+
+    // First byte after the data area, limited to the 256 pages reachable without SECTOR_SELECT
+    pub fn data_area_end(&self) -> u16 {
+        (DATA_AREA_START + self.cc.data_area_size).min(256 * UL_PAGE_SIZE as u16)
+    }
+
+    // Adds the area described by a Lock Control (`lock` set) or Memory Control TLV value
+    pub fn add_reserved(&mut self, value: &[u8; 3], lock: bool) -> Result<(), RFIDError> {
+        let page_address = (value[0] >> 4) as u16;
+        let byte_offset = (value[0] & 0x0F) as u16;
+        let bytes_per_page = 1u16 << (value[2] & 0x0F);
+        // Lock Control gives the number of lock bits, Memory Control bytes; 0 means 256
+        let size = if value[1] == 0 { 256 } else { value[1] as u16 };
+        let length = if lock { size.div_ceil(8) } else { size };
+
+        if self.reserved_count == MAX_RESERVED_AREAS {
+            return Err(RFIDError::InvalidNdef);
+        }
+        self.reserved[self.reserved_count] = (page_address * bytes_per_page + byte_offset, length);
+        self.reserved_count += 1;
+        Ok(())
+    }
+
+    pub fn is_reserved(&self, address: u16) -> bool {
+        self.reserved[..self.reserved_count]
+            .iter()
+            .any(|&(start, length)| address >= start && address < start + length)
+    }
+
+    // First usable address at or after `address`, None past the data area
+    pub fn usable(&self, mut address: u16) -> Option<u16> {
+        while address < self.data_area_end() {
+            if !self.is_reserved(address) {
+                return Some(address);
+            }
+            address += 1;
+        }
+        None
+    }
+
+    // Address `count` usable bytes after the usable address `address`
+    pub fn advance(&self, mut address: u16, count: u16) -> Option<u16> {
+        for _ in 0..count {
+            address = self.usable(address + 1)?;
+        }
+        Some(address)
+    }
+
+    // Number of usable bytes from `address` to the end of the data area
+    pub fn capacity_from(&self, address: u16) -> u16 {
+        (address..self.data_area_end()).filter(|&a| !self.is_reserved(a)).count() as u16
+    }
+}
+
+// Position of the NDEF Message TLV
+#[derive(Clone, Copy, PartialEq)]
+pub struct NdefTlv {
+    pub tlv_address: u16,
+    pub value_address: u16,
+    pub length: u16,
+}
+
+// Result of walking the TLV blocks of the data area
+#[derive(Clone, Copy, PartialEq)]
+pub struct Type2Info {
+    pub layout: Type2Layout,
+    pub ndef: Option<NdefTlv>,
+    // Where a new NDEF TLV goes: the existing one, or after the last control/proprietary TLV
+    pub ndef_insert_address: Option<u16>,
+}
+
+// Byte level access with a 4 page read cache and a pending page for writes
+struct Cursor<'m, M: Type2Memory> {
+    memory: &'m mut M,
+    cache_page: Option<u8>,
+    cache: [u8; MF_BLOCK_SIZE],
+    pending_page: Option<u8>,
+    pending: [u8; UL_PAGE_SIZE],
+}
+
+impl<'m, M: Type2Memory> Cursor<'m, M> {
+    fn new(memory: &'m mut M) -> Self {
+        Cursor {
+            memory,
+            cache_page: None,
+            cache: [0u8; MF_BLOCK_SIZE],
+            pending_page: None,
+            pending: [0u8; UL_PAGE_SIZE],
+        }
+    }
+
+    fn read_byte(&mut self, address: u16) -> Result<u8, RFIDError> {
+        let page = (address / UL_PAGE_SIZE as u16) as u8;
+        if self.pending_page == Some(page) {
+            return Ok(self.pending[address as usize % UL_PAGE_SIZE]);
+        }
+        let start = match self.cache_page {
+            Some(start) if page >= start && page - start < 4 => start,
+            _ => {
+                self.cache = self.memory.read_pages(page)?;
+                self.cache_page = Some(page);
+                page
+            }
+        };
+        Ok(self.cache[address as usize - start as usize * UL_PAGE_SIZE])
+    }
+
+    // Buffers the write, the page is written when the cursor moves to another page or on flush
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), RFIDError> {
+        let page = (address / UL_PAGE_SIZE as u16) as u8;
+        if self.pending_page != Some(page) {
+            self.flush()?;
+            let mut current = [0u8; UL_PAGE_SIZE];
+            for (i, byte) in current.iter_mut().enumerate() {
+                *byte = self.read_byte(page as u16 * UL_PAGE_SIZE as u16 + i as u16)?;
+            }
+            self.pending = current;
+            self.pending_page = Some(page);
+        }
+        self.pending[address as usize % UL_PAGE_SIZE] = value;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RFIDError> {
+        if let Some(page) = self.pending_page.take() {
+            self.memory.write_page(page, &self.pending)?;
+            if let Some(start) = self.cache_page {
+                if page >= start && page - start < 4 {
+                    let offset = (page - start) as usize * UL_PAGE_SIZE;
+                    self.cache[offset..offset + UL_PAGE_SIZE].copy_from_slice(&self.pending);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes `bytes` to consecutive usable addresses from `address`
+    fn write_bytes<I: Iterator<Item = u8>>(
+        &mut self,
+        layout: &Type2Layout,
+        address: u16,
+        bytes: I,
+    ) -> Result<(), RFIDError> {
+        let mut next = layout.usable(address);
+        for byte in bytes {
+            let address = next.ok_or(RFIDError::NoRoom)?;
+            self.write_byte(address, byte)?;
+            next = layout.usable(address + 1);
+        }
+        self.flush()
+    }
+}
+
+// Reads the Capability Container
+pub fn read_cc<M: Type2Memory>(memory: &mut M) -> Result<CapabilityContainer, RFIDError> {
+    let data = memory.read_pages(CC_PAGE)?;
+    CapabilityContainer::parse(&[data[0], data[1], data[2], data[3]])
+}
+
+// Reads the CC and walks the TLV blocks up to the first NDEF Message TLV or the Terminator
+pub fn scan<M: Type2Memory>(memory: &mut M) -> Result<Type2Info, RFIDError> {
+    let cc = read_cc(memory)?;
+    let mut cursor = Cursor::new(memory);
+    let mut info = Type2Info { layout: Type2Layout::new(cc), ndef: None, ndef_insert_address: None };
+    let layout = &mut info.layout;
+
+    let mut next = Some(DATA_AREA_START);
+    while let Some(tlv_address) = next.and_then(|address| layout.usable(address)) {
+        let tag = cursor.read_byte(tlv_address)?;
+        match tag {
+            TLV_NULL => {
+                // Padding up to the end of the area without a Terminator is free space too
+                info.ndef_insert_address.get_or_insert(tlv_address);
+                next = Some(tlv_address + 1);
+                continue;
+            }
+            TLV_TERMINATOR => {
+                info.ndef_insert_address = Some(tlv_address);
+                return Ok(info);
+            }
+            _ => {}
+        }
+
+        // 1 byte length, or 0xFF followed by a 2 byte length
+        let mut address = layout.advance(tlv_address, 1).ok_or(RFIDError::InvalidNdef)?;
+        let mut length = cursor.read_byte(address)? as u16;
+        if length == 0xFF {
+            address = layout.advance(address, 1).ok_or(RFIDError::InvalidNdef)?;
+            let high = cursor.read_byte(address)?;
+            address = layout.advance(address, 1).ok_or(RFIDError::InvalidNdef)?;
+            length = u16::from_be_bytes([high, cursor.read_byte(address)?]);
+        }
+        // Empty values sit at the end of the data area without a usable value address
+        let value_address = layout.usable(address + 1).unwrap_or(layout.data_area_end());
+        if length > layout.capacity_from(value_address) {
+            return Err(RFIDError::InvalidNdef);
+        }
+
+        match tag {
+            TLV_NDEF => {
+                info.ndef = Some(NdefTlv { tlv_address, value_address, length });
+                info.ndef_insert_address = Some(tlv_address);
+                return Ok(info);
+            }
+            TLV_LOCK_CONTROL | TLV_MEMORY_CONTROL => {
+                if length != 3 {
+                    return Err(RFIDError::InvalidNdef);
+                }
+                let mut value = [0u8; 3];
+                let mut address = value_address;
+                for (i, byte) in value.iter_mut().enumerate() {
+                    if i > 0 {
+                        address = layout.advance(address, 1).ok_or(RFIDError::InvalidNdef)?;
+                    }
+                    *byte = cursor.read_byte(address)?;
+                }
+                layout.add_reserved(&value, tag == TLV_LOCK_CONTROL)?;
+            }
+            // Proprietary and unknown TLVs are skipped
+            _ => {}
+        }
+        next = if length == 0 { Some(value_address) } else { layout.advance(value_address, length) };
+        info.ndef_insert_address = next;
+    }
+    Ok(info)
+}
+
+// Copies the NDEF message into `buffer` and returns its length (0 for an empty message)
+pub fn read_ndef<M: Type2Memory>(memory: &mut M, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+    let info = scan(memory)?;
+    if !info.layout.cc.is_readable() {
+        return Err(RFIDError::ReadOnly);
+    }
+    let ndef = info.ndef.ok_or(RFIDError::NotNdefFormatted)?;
+    let length = ndef.length as usize;
+    if buffer.len() < length {
+        return Err(RFIDError::NoRoom);
+    }
+
+    let mut cursor = Cursor::new(memory);
+    let mut address = ndef.value_address;
+    for (i, byte) in buffer[..length].iter_mut().enumerate() {
+        if i > 0 {
+            address = info.layout.advance(address, 1).ok_or(RFIDError::InvalidNdef)?;
+        }
+        *byte = cursor.read_byte(address)?;
+    }
+    Ok(length)
+}
+
+// Replaces the NDEF message. Following the Type 2 Tag spec the TLV is first written with
+// length 0, then the message and a Terminator TLV (if there is room), and the length last,
+// so an interrupted write leaves an empty message rather than a corrupt one.
+pub fn write_ndef<M: Type2Memory>(memory: &mut M, message: &[u8]) -> Result<(), RFIDError> {
+    let info = scan(memory)?;
+    if !info.layout.cc.is_writable() {
+        return Err(RFIDError::ReadOnly);
+    }
+    let layout = info.layout;
+    let address = info.ndef_insert_address.ok_or(RFIDError::NoRoom)?;
+    if message.len() > u16::MAX as usize - 4 {
+        return Err(RFIDError::NoRoom);
+    }
+
+    let length = message.len() as u16;
+    let (header, header_len): ([u8; 4], usize) = if length < 0xFF {
+        ([TLV_NDEF, length as u8, 0, 0], 2)
+    } else {
+        let [high, low] = length.to_be_bytes();
+        ([TLV_NDEF, 0xFF, high, low], 4)
+    };
+    let capacity = layout.capacity_from(address) as usize;
+    let needed = header_len + message.len();
+    if needed > capacity {
+        return Err(RFIDError::NoRoom);
+    }
+    let terminator = if needed < capacity { Some(TLV_TERMINATOR) } else { None };
+
+    let mut empty = header;
+    if header_len == 2 {
+        empty[1] = 0;
+    } else {
+        empty[2] = 0;
+        empty[3] = 0;
+    }
+
+    let mut cursor = Cursor::new(memory);
+    cursor.write_bytes(
+        &layout,
+        address,
+        empty[..header_len].iter().chain(message.iter()).copied().chain(terminator),
+    )?;
+    let length_address = layout.advance(address, 1).ok_or(RFIDError::NoRoom)?;
+    cursor.write_bytes(&layout, length_address, header[1..header_len].iter().copied())
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    pub fn type2_read_cc<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<CapabilityContainer, RFIDError> {
+        read_cc(&mut Type2Tag::new(self, serial))
+    }
+
+    // Reads the NDEF message of a Type 2 Tag into `buffer`, returns its length
+    pub fn type2_read_ndef<W: ufmt::uWrite>(&mut self, serial: &mut W, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+        read_ndef(&mut Type2Tag::new(self, serial), buffer)
+    }
+
+    // Writes `message` (an encoded NDEF message, see ndef::MessageWriter) to a Type 2 Tag
+    pub fn type2_write_ndef<W: ufmt::uWrite>(&mut self, serial: &mut W, message: &[u8]) -> Result<(), RFIDError> {
+        write_ndef(&mut Type2Tag::new(self, serial), message)
+    }
+}
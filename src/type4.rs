@@ -0,0 +1,232 @@
+// src/type4.rs
+// NFC Forum Type 4 Tag operation (mapping versions 2.0 and 3.0) over ISO-DEP APDUs: the NDEF
+// Tag Application, the Capability Container file and the NDEF file. The NDEF file holds the
+// message length (NLEN, 2 bytes, or ENLEN, 4 bytes, with an extended File Control TLV)
+// followed by the message, which ndef::records parses.
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::apdu::SHORT_LE_MAX;
+use crate::errors::RFIDError;
+use crate::rfid_rc522::RfidRc522;
+
+pub const NDEF_APPLICATION_AID: [u8; 7] = [0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01];
+pub const CC_FILE_ID: u16 = 0xE103;
+
+pub const T4_MAPPING_2_0: u8 = 0x20;
+pub const T4_MAPPING_3_0: u8 = 0x30;
+
+// File Control TLV types in the CC
+pub const TLV_NDEF_FILE_CONTROL: u8 = 0x04;
+pub const TLV_PROPRIETARY_FILE_CONTROL: u8 = 0x05;
+pub const TLV_EXTENDED_NDEF_FILE_CONTROL: u8 = 0x06;
+
+pub const T4_ACCESS_GRANTED: u8 = 0x00;
+pub const T4_ACCESS_DENIED: u8 = 0xFF;
+
+// CCLEN, mapping version, MLe, MLc and an NDEF File Control TLV
+const CC_MIN_LEN: usize = 15;
+// Largest CC we read, room for a few File Control TLVs
+const CC_MAX_LEN: usize = 64;
+// READ BINARY and UPDATE BINARY with short APDUs, P1 P2 offsets up to 0x7FFF
+const MAX_OFFSET: usize = 0x7FFF;
+const MAX_LC: usize = 255;
+
+// NDEF File Control TLV
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NdefFileControl {
+    pub file_id: u16,
+    pub max_size: u32, // Bytes, including the length field
+    pub read_access: u8,
+    pub write_access: u8,
+    pub extended: bool, // Extended TLV (0x06): the file starts with a 4 byte ENLEN
+}
+
+impl NdefFileControl {
+    // Bytes of the length field at the start of the NDEF file
+    pub fn length_size(&self) -> usize {
+        if self.extended { 4 } else { 2 }
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.read_access == T4_ACCESS_GRANTED
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.write_access == T4_ACCESS_GRANTED
+    }
+}
+
+// Capability Container file (E103)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Type4Cc {
+    pub mapping_version: u8, // Major version in the high nibble, minor in the low nibble
+    pub mle: u16,            // Maximum R-APDU data size for READ BINARY
+    pub mlc: u16,            // Maximum C-APDU data size for UPDATE BINARY
+    pub ndef_file: NdefFileControl,
+}
+
+impl Type4Cc {
+    // Parses the CC file, rejecting unsupported major versions and CCs without an NDEF File
+    // Control TLV. Proprietary File Control TLVs are skipped.
+    pub fn parse(data: &[u8]) -> Result<Self, RFIDError> {
+        if data.len() < CC_MIN_LEN {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+        let cc_len = (u16::from_be_bytes([data[0], data[1]]) as usize).min(data.len());
+        let mapping_version = data[2];
+        if !matches!(mapping_version >> 4, 2 | 3) {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+        let mle = u16::from_be_bytes([data[3], data[4]]);
+        let mlc = u16::from_be_bytes([data[5], data[6]]);
+        if mle < 0x000F || mlc == 0 {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+
+        let mut pos = 7;
+        while pos + 2 <= cc_len {
+            let (tag, length) = (data[pos], data[pos + 1] as usize);
+            let value = data.get(pos + 2..pos + 2 + length).ok_or(RFIDError::NotNdefFormatted)?;
+            let ndef_file = match (tag, length) {
+                (TLV_NDEF_FILE_CONTROL, 6) => Some(NdefFileControl {
+                    file_id: u16::from_be_bytes([value[0], value[1]]),
+                    max_size: u16::from_be_bytes([value[2], value[3]]) as u32,
+                    read_access: value[4],
+                    write_access: value[5],
+                    extended: false,
+                }),
+                (TLV_EXTENDED_NDEF_FILE_CONTROL, 8) => Some(NdefFileControl {
+                    file_id: u16::from_be_bytes([value[0], value[1]]),
+                    max_size: u32::from_be_bytes([value[2], value[3], value[4], value[5]]),
+                    read_access: value[6],
+                    write_access: value[7],
+                    extended: true,
+                }),
+                (TLV_NDEF_FILE_CONTROL | TLV_EXTENDED_NDEF_FILE_CONTROL, _) => return Err(RFIDError::NotNdefFormatted),
+                _ => None,
+            };
+            if let Some(ndef_file) = ndef_file {
+                return Ok(Type4Cc { mapping_version, mle, mlc, ndef_file });
+            }
+            pos += 2 + length;
+        }
+        Err(RFIDError::NotNdefFormatted)
+    }
+
+    // Bytes per READ BINARY, limited to short APDUs
+    pub fn read_chunk(&self) -> usize {
+        (self.mle as usize).min(SHORT_LE_MAX)
+    }
+
+    // Bytes per UPDATE BINARY, limited to short APDUs
+    pub fn write_chunk(&self) -> usize {
+        (self.mlc as usize).min(MAX_LC)
+    }
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Selects the NDEF Tag Application of a PICC activated with rats
+    pub fn type4_select_ndef_application<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<(), RFIDError> {
+        let mut response = [0u8; 34];
+        match self.select_aid(serial, &NDEF_APPLICATION_AID, &mut response) {
+            Err(RFIDError::Status(_)) => Err(RFIDError::NotNdefFormatted),
+            other => other.map(|_| ()),
+        }
+    }
+
+    // Selects and reads the CC file of the selected NDEF Tag Application
+    pub fn type4_read_cc<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<Type4Cc, RFIDError> {
+        self.select_file(serial, CC_FILE_ID)?;
+        let mut data = [0u8; CC_MAX_LEN + 2];
+        let length = self.read_binary(serial, 0, &mut data[..CC_MIN_LEN + 2])?;
+        if length < 2 {
+            return Err(RFIDError::NotNdefFormatted);
+        }
+        let cc_len = (u16::from_be_bytes([data[0], data[1]]) as usize).min(CC_MAX_LEN);
+        let mut read = length;
+        while read < cc_len {
+            let mut chunk = [0u8; CC_MAX_LEN + 2];
+            let count = self.read_binary(serial, read as u16, &mut chunk[..cc_len - read + 2])?;
+            if count == 0 {
+                break;
+            }
+            data[read..read + count].copy_from_slice(&chunk[..count]);
+            read += count;
+        }
+        Type4Cc::parse(&data[..read])
+    }
+
+    // Selects the NDEF application and file and copies the NDEF message into `buffer`,
+    // returns its length (0 for an empty message)
+    pub fn type4_read_ndef<W: ufmt::uWrite>(&mut self, serial: &mut W, buffer: &mut [u8]) -> Result<usize, RFIDError> {
+        self.type4_select_ndef_application(serial)?;
+        let cc = self.type4_read_cc(serial)?;
+        let file = cc.ndef_file;
+        if !file.is_readable() {
+            return Err(RFIDError::ReadOnly);
+        }
+        self.select_file(serial, file.file_id)?;
+
+        let size = file.length_size();
+        let mut header = [0u8; 4 + 2];
+        if self.read_binary(serial, 0, &mut header[..size + 2])? != size {
+            return Err(RFIDError::InvalidNdef);
+        }
+        // In u32, a 4 byte ENLEN does not fit the 16 bit usize of AVR
+        let length = header[..size].iter().fold(0u32, |length, &byte| (length << 8) | byte as u32);
+        let end = length.checked_add(size as u32).ok_or(RFIDError::InvalidNdef)?;
+        if end > file.max_size || end > MAX_OFFSET as u32 + 1 {
+            return Err(RFIDError::InvalidNdef);
+        }
+        let length = usize::try_from(length).map_err(|_| RFIDError::InvalidNdef)?;
+        let message = buffer.get_mut(..length).ok_or(RFIDError::NoRoom)?;
+
+        let mut chunk = [0u8; SHORT_LE_MAX + 2];
+        let mut read = 0;
+        while read < length {
+            let count = cc.read_chunk().min(length - read);
+            let received = self.read_binary(serial, (size + read) as u16, &mut chunk[..count + 2])?;
+            if received == 0 || received > count {
+                return Err(RFIDError::InvalidNdef);
+            }
+            message[read..read + received].copy_from_slice(&chunk[..received]);
+            read += received;
+        }
+        Ok(length)
+    }
+
+    // Replaces the NDEF message with `message` (an encoded NDEF message, see
+    // ndef::MessageWriter). The length is set to 0 first and written last, so an interrupted
+    // write leaves an empty message rather than a corrupt one.
+    pub fn type4_write_ndef<W: ufmt::uWrite>(&mut self, serial: &mut W, message: &[u8]) -> Result<(), RFIDError> {
+        self.type4_select_ndef_application(serial)?;
+        let cc = self.type4_read_cc(serial)?;
+        let file = cc.ndef_file;
+        if !file.is_writable() {
+            return Err(RFIDError::ReadOnly);
+        }
+        let size = file.length_size();
+        let end = u32::try_from(message.len())
+            .ok()
+            .and_then(|length| length.checked_add(size as u32))
+            .ok_or(RFIDError::NoRoom)?;
+        if end > file.max_size || end > MAX_OFFSET as u32 + 1 {
+            return Err(RFIDError::NoRoom);
+        }
+        self.select_file(serial, file.file_id)?;
+
+        let length = (message.len() as u32).to_be_bytes();
+        self.update_binary(serial, 0, &[0u8; 4][..size])?;
+        let mut offset = size;
+        for chunk in message.chunks(cc.write_chunk()) {
+            self.update_binary(serial, offset as u16, chunk)?;
+            offset += chunk.len();
+        }
+        self.update_binary(serial, 0, &length[4 - size..])
+    }
+}
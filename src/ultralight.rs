@@ -0,0 +1,99 @@
+// src/ultralight.rs
+// MIFARE Ultralight and NTAG (NFC Forum Type 2 Tag) page access
+
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::errors::RFIDError;
+use crate::mifare::MF_BLOCK_SIZE;
+use crate::registers::{PICC_CMD_NTAG_FAST_READ, PICC_CMD_UL_WRITE};
+use crate::rfid_rc522::RfidRc522;
+
+pub const UL_PAGE_SIZE: usize = 4;
+
+// The MFRC522 FIFO holds 64 bytes, so FAST_READ is split into runs of 15 pages (60 bytes + CRC_A)
+const FAST_READ_MAX_PAGES: u8 = 15;
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Reads 4 pages (16 bytes) starting at `page`. Reading past the last page rolls over to page 0.
+    pub fn ultralight_read<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        page: u8,
+    ) -> Result<[u8; MF_BLOCK_SIZE], RFIDError> {
+        // Same command and answer as a MIFARE Classic block read
+        self.mifare_read(serial, page)
+    }
+
+    // Writes one 4 byte page (WRITE, 0xA2)
+    pub fn ultralight_write<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        page: u8,
+        data: &[u8; UL_PAGE_SIZE],
+    ) -> Result<(), RFIDError> {
+        let command = [PICC_CMD_UL_WRITE, page, data[0], data[1], data[2], data[3]];
+        self.mifare_transceive(serial, &command, false)
+    }
+
+    // Writes one page with the 16 byte MIFARE Classic write frame (COMPATIBILITY WRITE, 0xA0).
+    // Only the first 4 bytes are written, the rest of the frame is zero.
+    pub fn ultralight_compat_write<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        page: u8,
+        data: &[u8; UL_PAGE_SIZE],
+    ) -> Result<(), RFIDError> {
+        let mut block = [0u8; MF_BLOCK_SIZE];
+        block[..UL_PAGE_SIZE].copy_from_slice(data);
+        self.mifare_write(serial, page, &block)
+    }
+
+    // Reads pages `start_page` to `end_page` (inclusive) into `buffer` with NTAG FAST_READ (0x3A).
+    // Returns the number of bytes read.
+    pub fn ntag_fast_read<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        start_page: u8,
+        end_page: u8,
+        buffer: &mut [u8],
+    ) -> Result<usize, RFIDError> {
+        if end_page < start_page {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let total = (end_page - start_page) as usize + 1;
+        if buffer.len() < total * UL_PAGE_SIZE {
+            return Err(RFIDError::NoRoom);
+        }
+
+        let mut page = start_page;
+        let mut offset = 0;
+        loop {
+            let last = end_page.min(page.saturating_add(FAST_READ_MAX_PAGES - 1));
+            let len = (last - page + 1) as usize * UL_PAGE_SIZE;
+
+            let mut command = [PICC_CMD_NTAG_FAST_READ, page, last, 0, 0];
+            let mut crc = [0u8; 2];
+            self.pcd_calculate_crc(serial, &command[..3], &mut crc)?;
+            command[3] = crc[0];
+            command[4] = crc[1];
+
+            let mut response = [0u8; FAST_READ_MAX_PAGES as usize * UL_PAGE_SIZE + 2];
+            let mut valid_bits = 0;
+            let received = self.transceive_data(serial, &command, &mut response, &mut valid_bits, 0, true)?;
+            if received != len + 2 {
+                return Err(RFIDError::InvalidResponse);
+            }
+            buffer[offset..offset + len].copy_from_slice(&response[..len]);
+            offset += len;
+
+            if last == end_page {
+                return Ok(offset);
+            }
+            page = last + 1;
+        }
+    }
+}
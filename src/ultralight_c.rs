@@ -0,0 +1,190 @@
+// src/ultralight_c.rs
+// MIFARE Ultralight C: 2K3DES mutual authentication and the key/access configuration pages.
+// The handshake is three pass:
+//   reader -> 0x1A 0x00, tag -> 0xAF ek(RndB), reader -> 0xAF ek(RndA || RndB'), tag -> 0x00 ek(RndA')
+// where ' is a rotation left by one byte and ek is 2K3DES in CBC mode, chaining across the frames.
+
+use des::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use des::TdesEde2;
+use embedded_hal::spi::SpiBus;
+use embedded_hal::digital::OutputPin;
+use crate::errors::RFIDError;
+use crate::registers::{PICC_CMD_UL_C_AUTH, PICC_CMD_UL_C_AUTH_CONTINUE};
+use crate::rfid_rc522::RfidRc522;
+
+// Default 2K3DES key of new cards. Their key pages read "BREAKMEIFYOUCAN!" in memory order,
+// see key_pages for the byte order.
+pub const UL_C_DEFAULT_KEY: [u8; 16] = *b"IEMKAERB!NACUOYF";
+
+// Configuration pages
+pub const UL_C_AUTH0_PAGE: u8 = 0x2A;
+pub const UL_C_AUTH1_PAGE: u8 = 0x2B;
+pub const UL_C_KEY_PAGE: u8 = 0x2C;
+// AUTH0 value that turns protection off (first page after the user memory)
+pub const UL_C_AUTH_DISABLED: u8 = 0x30;
+
+// Reader side of the 3DES authentication. `rnd_a` must be fresh random bytes for every
+// authentication; the MFRC522 has no random number generator, so the caller supplies them.
+pub struct UltralightCAuth {
+    cipher: TdesEde2,
+    rnd_a: [u8; 8],
+    iv: [u8; 8],
+}
+
+impl UltralightCAuth {
+    pub fn new(key: &[u8; 16], rnd_a: &[u8; 8]) -> Self {
+        UltralightCAuth {
+            cipher: TdesEde2::new(key.into()),
+            rnd_a: *rnd_a,
+            iv: [0u8; 8],
+        }
+    }
+
+    // Takes ek(RndB) from the tag and returns ek(RndA || RndB') for the second frame
+    pub fn answer(&mut self, rnd_b_enc: &[u8; 8]) -> [u8; 16] {
+        let mut rnd_b = *rnd_b_enc;
+        self.decrypt_block(&mut rnd_b);
+        rnd_b.rotate_left(1);
+
+        let mut answer = [0u8; 16];
+        answer[..8].copy_from_slice(&self.rnd_a);
+        answer[8..].copy_from_slice(&rnd_b);
+        for chunk in answer.chunks_exact_mut(8) {
+            let block: &mut [u8; 8] = chunk.try_into().unwrap();
+            self.encrypt_block(block);
+        }
+        answer
+    }
+
+    // Checks ek(RndA') from the tag, proving it holds the same key
+    pub fn verify(&mut self, rnd_a_enc: &[u8; 8]) -> bool {
+        let mut rnd_a = *rnd_a_enc;
+        self.decrypt_block(&mut rnd_a);
+        let mut expected = self.rnd_a;
+        expected.rotate_left(1);
+        rnd_a == expected
+    }
+
+    fn encrypt_block(&mut self, block: &mut [u8; 8]) {
+        for (byte, iv) in block.iter_mut().zip(self.iv.iter()) {
+            *byte ^= iv;
+        }
+        self.cipher.encrypt_block(block.into());
+        self.iv = *block;
+    }
+
+    fn decrypt_block(&mut self, block: &mut [u8; 8]) {
+        let ciphertext = *block;
+        self.cipher.decrypt_block(block.into());
+        for (byte, iv) in block.iter_mut().zip(self.iv.iter()) {
+            *byte ^= iv;
+        }
+        self.iv = ciphertext;
+    }
+}
+
+// The key is stored with the bytes of each 8 byte half in reverse order, 4 bytes per page
+pub fn key_pages(key: &[u8; 16]) -> [[u8; 4]; 4] {
+    let mut pages = [[0u8; 4]; 4];
+    for (i, page) in pages.iter_mut().enumerate() {
+        let half = (i / 2) * 8;
+        let start = half + 7 - (i % 2) * 4;
+        for (j, byte) in page.iter_mut().enumerate() {
+            *byte = key[start - j];
+        }
+    }
+    pages
+}
+
+impl<SPI, CS> RfidRc522<SPI, CS>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin<Error = core::convert::Infallible>,
+{
+    // Runs the 3DES mutual authentication. Access stays granted until the tag is halted or
+    // leaves the field.
+    pub fn ultralight_c_authenticate<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        key: &[u8; 16],
+        rnd_a: &[u8; 8],
+    ) -> Result<(), RFIDError> {
+        let mut auth = UltralightCAuth::new(key, rnd_a);
+
+        // 0xAF || ek(RndB) + CRC_A
+        let mut response = [0u8; 11];
+        self.ultralight_c_exchange(serial, &[PICC_CMD_UL_C_AUTH, 0x00], &mut response)?;
+        if response[0] != PICC_CMD_UL_C_AUTH_CONTINUE {
+            return Err(RFIDError::InvalidResponse);
+        }
+        let mut rnd_b_enc = [0u8; 8];
+        rnd_b_enc.copy_from_slice(&response[1..9]);
+
+        let mut frame = [0u8; 17];
+        frame[0] = PICC_CMD_UL_C_AUTH_CONTINUE;
+        frame[1..].copy_from_slice(&auth.answer(&rnd_b_enc));
+
+        // 0x00 || ek(RndA') + CRC_A
+        let mut response = [0u8; 11];
+        self.ultralight_c_exchange(serial, &frame, &mut response)?;
+        let mut rnd_a_enc = [0u8; 8];
+        rnd_a_enc.copy_from_slice(&response[1..9]);
+        if response[0] != 0x00 || !auth.verify(&rnd_a_enc) {
+            return Err(RFIDError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    // Writes a new 2K3DES key to pages 0x2C-0x2F. The key pages can never be read back,
+    // authenticate with the new key to check it.
+    pub fn ultralight_c_write_key<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        key: &[u8; 16],
+    ) -> Result<(), RFIDError> {
+        for (i, page) in key_pages(key).iter().enumerate() {
+            self.ultralight_write(serial, UL_C_KEY_PAGE + i as u8, page)?;
+        }
+        Ok(())
+    }
+
+    // Sets AUTH0 (first protected page, UL_C_AUTH_DISABLED for none) and AUTH1
+    // (`write_only`: reads stay free and only writes need authentication)
+    pub fn ultralight_c_set_auth<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        auth0: u8,
+        write_only: bool,
+    ) -> Result<(), RFIDError> {
+        self.ultralight_write(serial, UL_C_AUTH1_PAGE, &[write_only as u8, 0, 0, 0])?;
+        self.ultralight_write(serial, UL_C_AUTH0_PAGE, &[auth0, 0, 0, 0])
+    }
+
+    // Reads AUTH0 and the AUTH1 write-only flag
+    pub fn ultralight_c_get_auth<W: ufmt::uWrite>(&mut self, serial: &mut W) -> Result<(u8, bool), RFIDError> {
+        let data = self.ultralight_read(serial, UL_C_AUTH0_PAGE)?;
+        Ok((data[0], data[4] & 0x01 != 0))
+    }
+
+    pub(crate) fn ultralight_c_exchange<W: ufmt::uWrite>(
+        &mut self,
+        serial: &mut W,
+        command: &[u8],
+        response: &mut [u8; 11],
+    ) -> Result<(), RFIDError> {
+        let len = command.len();
+        let mut frame = [0u8; 19];
+        frame[..len].copy_from_slice(command);
+        let mut crc = [0u8; 2];
+        self.pcd_calculate_crc(serial, command, &mut crc)?;
+        frame[len] = crc[0];
+        frame[len + 1] = crc[1];
+
+        let mut valid_bits = 0;
+        let received = self.transceive_data(serial, &frame[..len + 2], response, &mut valid_bits, 0, true)?;
+        if received != response.len() {
+            return Err(RFIDError::InvalidResponse);
+        }
+        Ok(())
+    }
+}